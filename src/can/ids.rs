@@ -1,7 +1,7 @@
 use num_derive::FromPrimitive;
 
 
-#[derive(FromPrimitive)]
+#[derive(FromPrimitive, Clone, Copy, PartialEq)]
 pub enum CanIds {
     CanIdMostImportant=0x000,
 
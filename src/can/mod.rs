@@ -5,7 +5,7 @@ use log::{error, info};
 use tokio::sync::broadcast;
 use socketcan::{tokio::CanSocket, CanFrame, Id};
 
-use crate::SETTINGS;
+use crate::{helper::bus::{BusExt, BusReceiver}, SETTINGS};
 
 pub type CanSender = broadcast::Sender<CanFrame>;
 pub type CanReceiver = broadcast::Sender<CanFrame>;
@@ -32,11 +32,12 @@ impl CAN {
             }
         };
         while let Some(Ok(frame)) = sock_rx.next().await {
-            receiver_tx.send(frame).unwrap();
+            // Fails only while nobody is subscribed
+            let _ = receiver_tx.send(frame);
         }
     }
 
-    async fn tx_to_can(interface: String, sender_rx: CanSender) {
+    async fn tx_to_can(interface: String, mut rx: BusReceiver<CanFrame>) {
         let sock_tx =  match CanSocket::open(&interface) {
             Ok(port) => port,
             Err(_err) => {
@@ -44,25 +45,35 @@ impl CAN {
                 return;
             }
         };
-        let mut rx = sender_rx.subscribe();
         loop {
-            let msg = rx.recv().await.unwrap();
+            let Some(msg) = rx.recv().await else { break };
             let _res = sock_tx.write_frame(msg).unwrap().await;
         }
     }
 
+    fn channels() -> Self {
+        let capacity = SETTINGS.get::<usize>("bus.can_capacity").unwrap();
+        let (receiver, _receiver_rx) = broadcast::channel::<CanFrame>(capacity);
+        let (sender, _sender_rx) = broadcast::channel::<CanFrame>(capacity);
+        CAN { sender, receiver }
+    }
+
+    // Bus of an additional interface, e.g. a separate NMEA 2000 network
+    pub fn open(interface: &str) -> Self {
+        let can = Self::channels();
+        tokio::spawn(Self::can_to_rx(interface.to_string(), can.receiver.clone()));
+        // Subscribed right away, so frames sent before the socket is open are not lost (e.g. the N2K address claim)
+        tokio::spawn(Self::tx_to_can(interface.to_string(), can.sender.subscribe_as("can_tx")));
+        can
+    }
+
     pub fn start() -> Self {
-        let (receiver, _receiver_rx) = broadcast::channel::<CanFrame>(64);
-        let (sender, _sender_rx) = broadcast::channel::<CanFrame>(64);
-        
         if SETTINGS.get::<bool>("can.enabled").unwrap() {
             info!("CAN enabled!");
-            let interface = SETTINGS.get::<String>("can.interface").unwrap();
-            tokio::spawn(Self::can_to_rx(interface.clone(), receiver.clone()));
-            tokio::spawn(Self::tx_to_can(interface, sender.clone()));
+            Self::open(&SETTINGS.get::<String>("can.interface").unwrap())
+        } else {
+            Self::channels()
         }
-
-        CAN { sender, receiver }
     }
 
 }
\ No newline at end of file
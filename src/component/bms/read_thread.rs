@@ -3,7 +3,7 @@ use socketcan::EmbeddedFrame;
 use wannsea_types::MessageId;
 use wannsea_types::boat_core_message::Value;
 
-use crate::helper::MetricSenderExt;
+use crate::helper::{bus::BusExt, MetricSenderExt};
 use crate::{can::{CanReceiver, get_can_id}, helper::MetricSender};
 
 use super::{structs::{BmsFunction, BatteryPack}, BatteryPackNotifier};
@@ -56,9 +56,9 @@ impl BmsReadThread {
     }
 
     async fn start_receiving(&self) {
-        let mut receiver = self.can_receiver.subscribe();
+        let mut receiver = self.can_receiver.subscribe_as("bms");
         loop {
-            let frame = receiver.recv().await.unwrap();
+            let Some(frame) = receiver.recv().await else { break };
 
             if frame.dlc() != 8 {
                 continue;
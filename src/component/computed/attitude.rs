@@ -0,0 +1,120 @@
+use log::{info, warn};
+use nalgebra::{UnitQuaternion, Vector3};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::helper::{bus::BusExt, imu_frame, MetricSender, MetricSenderExt};
+use crate::SETTINGS;
+
+// Roll, pitch and heading from the IMU rotation vector and heave from its linear acceleration, both in the
+// right handed frame of helper::imu_frame like the sensor fusion.
+// Heading is magnetic, the true heading adds the declination which is either fixed in the config
+// or taken from the magnetic variation reported by the GPS receiver ("auto").
+pub struct Attitude {
+    metric_sender: MetricSender
+}
+
+struct AttitudeState {
+    orientation: Option<UnitQuaternion<f32>>,
+    declination: Option<f32>,
+    heave_velocity: f32,
+    heave: f32,
+    last_accel_ns: u128
+}
+
+impl Attitude {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        Attitude { metric_sender }
+    }
+
+    fn wrap_degrees(angle: f32) -> f32 {
+        (angle % 360.0 + 360.0) % 360.0
+    }
+
+    fn send_attitude(state: &AttitudeState, offsets: &(f32, f32, f32), metric_sender: &MetricSender) {
+        let orientation = match state.orientation {
+            Some(orientation) => orientation,
+            None => return
+        };
+        let (roll, pitch, yaw) = orientation.euler_angles();
+        let (roll_offset, pitch_offset, heading_offset) = offsets;
+
+        // Yaw is counter clockwise, compass heading clockwise from north
+        let heading_magnetic = Self::wrap_degrees(heading_offset - yaw.to_degrees());
+        metric_sender.send_now(MessageId::AttitudeRoll, Value::Float(roll.to_degrees() - roll_offset)).unwrap();
+        metric_sender.send_now(MessageId::AttitudePitch, Value::Float(pitch.to_degrees() - pitch_offset)).unwrap();
+        metric_sender.send_now(MessageId::AttitudeHeadingMagnetic, Value::Float(heading_magnetic)).unwrap();
+        if let Some(declination) = state.declination {
+            metric_sender.send_now(MessageId::AttitudeHeadingTrue, Value::Float(Self::wrap_degrees(heading_magnetic + declination))).unwrap();
+        }
+    }
+
+    // Vertical acceleration is integrated twice, both integrators leak with the given time constant
+    // so the heave stays around zero instead of drifting away with the sensor bias.
+    fn update_heave(state: &mut AttitudeState, linear_accel: Vector3<f32>, ts_ns: u128, time_constant: f32, metric_sender: &MetricSender) {
+        let orientation = match state.orientation {
+            Some(orientation) => orientation,
+            None => return
+        };
+        if state.last_accel_ns == 0 || ts_ns <= state.last_accel_ns {
+            state.last_accel_ns = ts_ns;
+            return;
+        }
+        let dt = (ts_ns - state.last_accel_ns) as f32 / 1e9;
+        state.last_accel_ns = ts_ns;
+
+        let vertical_accel = (orientation * linear_accel).z;
+        let alpha = time_constant / (time_constant + dt);
+        state.heave_velocity = alpha * (state.heave_velocity + vertical_accel * dt);
+        state.heave = alpha * (state.heave + state.heave_velocity * dt);
+
+        metric_sender.send_now(MessageId::HeaveVelocity, Value::Float(state.heave_velocity)).unwrap();
+        metric_sender.send_now(MessageId::Heave, Value::Float(state.heave)).unwrap();
+    }
+
+    async fn run(metric_sender: MetricSender) {
+        let offsets = (
+            SETTINGS.get::<f32>("attitude.roll_offset").unwrap(),
+            SETTINGS.get::<f32>("attitude.pitch_offset").unwrap(),
+            SETTINGS.get::<f32>("attitude.heading_offset").unwrap()
+        );
+        let heave_time_constant = SETTINGS.get::<f32>("attitude.heave_time_constant").unwrap();
+        // Either a fixed declination in degrees (east positive) or "auto"
+        let fixed_declination = SETTINGS.get::<String>("attitude.declination").unwrap().parse::<f32>().ok();
+
+        let mut state = AttitudeState { orientation: None, declination: fixed_declination, heave_velocity: 0.0, heave: 0.0, last_accel_ns: 0 };
+        let mut receiver = metric_sender.subscribe_as("attitude");
+        loop {
+            let Some(metric) = receiver.recv().await else { break };
+
+            if metric.id() == MessageId::ImuRotation {
+                match imu_frame::orientation(metric.value.as_ref()) {
+                    Some(orientation) => {
+                        state.orientation = Some(orientation);
+                        Self::send_attitude(&state, &offsets, &metric_sender);
+                    },
+                    None => warn!("Rotation unexpected metric format")
+                }
+            }
+            else if metric.id() == MessageId::ImuLinearAcceleration {
+                match imu_frame::vector(metric.value.as_ref()) {
+                    Some(linear_accel) => Self::update_heave(&mut state, linear_accel, metric.get_ts_ns(), heave_time_constant, &metric_sender),
+                    None => warn!("Linear acceleration unexpected metric format")
+                }
+            }
+            else if metric.id() == MessageId::GpsMagneticVariation && fixed_declination.is_none() {
+                match metric.value {
+                    Some(Value::Double(variation)) => state.declination = Some(variation as f32),
+                    _ => warn!("Magnetic variation unexpected metric format")
+                }
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("attitude.enabled").unwrap() {
+            info!("Computed Attitude enabled!");
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
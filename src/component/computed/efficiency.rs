@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::helper::{bus::BusExt, MetricSender, MetricSenderExt};
+use crate::SETTINGS;
+
+// Online map of propulsion efficiency (Wh/nm) per motor RPM bin and sea state.
+// Power in W divided by speed in knots is directly Wh per nautical mile.
+pub struct Efficiency {
+    metric_sender: MetricSender
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Copy)]
+struct Cell {
+    samples: u32,
+    // Averages in W and knots
+    power: f32,
+    speed: f32
+}
+
+impl Cell {
+    // Plain mean until max_samples, then a moving average so the map follows changes of hull and propeller
+    fn add(&mut self, power: f32, speed: f32, max_samples: u32) {
+        self.samples = (self.samples + 1).min(max_samples);
+        let weight = 1.0 / self.samples as f32;
+        self.power += weight * (power - self.power);
+        self.speed += weight * (speed - self.speed);
+    }
+
+    fn wh_per_nm(&self) -> f32 {
+        self.power / self.speed
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct EfficiencyMap {
+    // Index is the sea state, key the ERPM bin
+    sea_states: Vec<BTreeMap<u32, Cell>>
+}
+
+impl EfficiencyMap {
+    async fn load(path: &str, sea_states: usize) -> Self {
+        let mut map: EfficiencyMap = match tokio::fs::read_to_string(path).await {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|err| {
+                warn!("Could not parse efficiency map {}: {}. Starting empty", path, err);
+                EfficiencyMap::default()
+            }),
+            Err(_) => EfficiencyMap::default()
+        };
+        map.sea_states.resize(sea_states, BTreeMap::new());
+        map
+    }
+
+    async fn save(&self, path: &str) -> std::io::Result<()> {
+        if let Some(dir) = Path::new(path).parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(path, serde_json::to_string(self)?).await
+    }
+
+    // Bin with the lowest energy per distance that has been sailed often enough
+    fn best_bin(&self, sea_state: usize, min_samples: u32) -> Option<u32> {
+        self.sea_states[sea_state]
+            .iter()
+            .filter(|(_bin, cell)| cell.samples >= min_samples && cell.speed > 0.0)
+            .min_by(|a, b| a.1.wh_per_nm().total_cmp(&b.1.wh_per_nm()))
+            .map(|(bin, _cell)| *bin)
+    }
+}
+
+struct EfficiencyState {
+    erpm: Option<i32>,
+    power: Option<f32>,
+    heave: VecDeque<f32>,
+    last_save: Instant
+}
+
+impl EfficiencyState {
+    // Standard deviation of the heave in metres
+    fn heave_deviation(&self) -> f32 {
+        let n = self.heave.len() as f32;
+        let mean = self.heave.iter().sum::<f32>() / n;
+        (self.heave.iter().map(|h| (h - mean).powi(2)).sum::<f32>() / n).sqrt()
+    }
+}
+
+impl Efficiency {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        Efficiency { metric_sender }
+    }
+
+    async fn run(metric_sender: MetricSender) {
+        let map_file = SETTINGS.get::<String>("efficiency.map_file").unwrap();
+        let rpm_bin_size = SETTINGS.get::<u32>("efficiency.rpm_bin_size").unwrap();
+        if rpm_bin_size == 0 {
+            error!("efficiency.rpm_bin_size must be at least 1, efficiency map disabled");
+            return;
+        }
+        let min_speed = SETTINGS.get::<f32>("efficiency.min_speed").unwrap();
+        let min_samples = SETTINGS.get::<u32>("efficiency.min_samples").unwrap();
+        let max_samples = SETTINGS.get::<u32>("efficiency.max_samples").unwrap();
+        let sea_state_limits = SETTINGS.get::<Vec<f32>>("efficiency.sea_state_limits").unwrap();
+        let sea_state_window = SETTINGS.get::<usize>("efficiency.sea_state_window").unwrap();
+        let save_interval = SETTINGS.get::<u64>("efficiency.save_interval").unwrap();
+
+        let mut map = EfficiencyMap::load(&map_file, sea_state_limits.len() + 1).await;
+        let mut state = EfficiencyState { erpm: None, power: None, heave: VecDeque::with_capacity(sea_state_window), last_save: Instant::now() };
+
+        let mut receiver = metric_sender.subscribe_as("efficiency");
+        loop {
+            let Some(metric) = receiver.recv().await else { break };
+            let id = metric.id();
+            if !matches!(id, MessageId::EscRpm | MessageId::EscTotalInPower | MessageId::Heave | MessageId::GpsSpeed) {
+                continue;
+            }
+
+            match (id, metric.value) {
+                (MessageId::EscRpm, Some(Value::Int32(erpm))) => state.erpm = Some(erpm),
+                (MessageId::EscTotalInPower, Some(Value::Float(power))) => state.power = Some(power),
+                (MessageId::Heave, Some(Value::Float(heave))) => {
+                    if state.heave.len() == sea_state_window {
+                        state.heave.pop_front();
+                    }
+                    state.heave.push_back(heave);
+                },
+                (MessageId::GpsSpeed, Some(Value::Double(speed))) => {
+                    let speed = speed as f32;
+                    let (erpm, power) = match (state.erpm, state.power) {
+                        (Some(erpm), Some(power)) if speed >= min_speed => (erpm, power),
+                        _ => continue
+                    };
+
+                    // Without heave data everything counts as calm water
+                    let sea_state = if state.heave.is_empty() {
+                        0
+                    } else {
+                        let deviation = state.heave_deviation();
+                        sea_state_limits.iter().filter(|limit| deviation > **limit).count()
+                    };
+                    let bin = erpm.unsigned_abs() / rpm_bin_size;
+                    map.sea_states[sea_state].entry(bin).or_default().add(power, speed, max_samples);
+
+                    metric_sender.send_now(MessageId::EfficiencyWhPerNm, Value::Float(power / speed)).unwrap();
+                    metric_sender.send_now(MessageId::EfficiencySeaState, Value::Uint32(sea_state as u32)).unwrap();
+                    if let Some(best) = map.best_bin(sea_state, min_samples) {
+                        // Centre of the bin, in ERPM like EscRpm
+                        let rpm = (best * rpm_bin_size + rpm_bin_size / 2) as i32;
+                        metric_sender.send_now(MessageId::EfficiencyRecommendedRpm, Value::Int32(rpm)).unwrap();
+                    }
+
+                    if state.last_save.elapsed().as_secs() >= save_interval {
+                        state.last_save = Instant::now();
+                        if let Err(err) = map.save(&map_file).await {
+                            warn!("Could not save efficiency map to {}: {}", map_file, err);
+                        }
+                    }
+                },
+                _ => warn!("Unexpected {} format", id.as_str_name())
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("efficiency.enabled").unwrap() {
+            info!("Computed Efficiency enabled!");
+
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
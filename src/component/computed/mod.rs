@@ -1,2 +1,5 @@
 pub mod sensor_fusion;
-pub mod power;
\ No newline at end of file
+pub mod power;
+pub mod attitude;
+pub mod water_speed;
+pub mod efficiency;
\ No newline at end of file
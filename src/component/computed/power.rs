@@ -4,7 +4,7 @@ use socketcan::EmbeddedFrame;
 use wannsea_types::boat_core_message::Value;
 use wannsea_types::MessageId;
 
-use crate::helper::MetricSenderExt;
+use crate::helper::{bus::BusExt, MetricSenderExt};
 use crate::SETTINGS;
 use crate::{can::{CanReceiver, get_can_id}, helper::MetricSender};
 
@@ -28,12 +28,12 @@ impl MotorPower {
     }
 
     async fn start_receiving(sender: MetricSender) {
-        let mut receiver = sender.subscribe();
+        let mut receiver = sender.subscribe_as("motor_power");
         let mut last_current: f32 = 0.0f32;
         let mut last_voltage: f32 = 0.0f32;
 
         loop {
-            let metric = receiver.recv().await.unwrap();
+            let Some(metric) = receiver.recv().await else { break };
 
 
             if metric.id() == MessageId::EscTotalInCurrent {
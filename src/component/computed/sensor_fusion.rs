@@ -1,106 +1,282 @@
 use eskf;
 use log::{info, warn};
-use nalgebra::{Vector3, Point3};
+use nalgebra::{Point3, Vector3};
 use wannsea_types::{boat_core_message::Value, Floats, MessageId};
 use std::time::Duration;
 
-use crate::{helper::{MetricSender, MetricSenderExt}, SETTINGS};
+use crate::{helper::{bus::BusExt, geo::{GeoPoint, LocalProjection}, imu_frame, MetricSender, MetricSenderExt}, SETTINGS};
+
+const KNOTS_TO_MS: f64 = 1852.0 / 3600.0;
 
 pub struct SensorFusion {
     metric_sender: MetricSender
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum FusionHealth {
+    Ok = 0,
+    // Measurements were rejected as outliers or an update failed
+    Degraded = 1,
+    // Filter was reset and waits for a new fix
+    Reset = 2
+}
+
+// The filter runs in a local east/north/up frame (metres) anchored at the first GPS fix
+struct FusionState {
+    projection: Option<LocalProjection>,
+    last_update_ns: Option<u128>,
+    hdop: f32,
+    gps_speed_knots: Option<f64>,
+    rejections: u32,
+    // GPS fixes since start or the last reset
+    fixes: u32,
+    health: FusionHealth,
+    // Orientation was seeded from the IMU rotation vector, the filter only predicts after that
+    oriented: bool
+}
+
+struct FusionConfig {
+    // Standard deviation of a GPS fix in metres at HDOP 1
+    gps_uere: f32,
+    gps_velocity_variance: f32,
+    // Larger gaps between IMU samples are not integrated
+    max_dt: Duration,
+    // Normalized innovation squared above which a measurement is rejected
+    nis_threshold: f32,
+    // Consecutive rejections after which the filter is considered diverged
+    max_rejections: u32,
+    // Fixes after a reset that are not gated, the initial covariance does not yet reflect the real uncertainty
+    warmup_fixes: u32,
+    // Position standard deviation (m) after which the filter is considered diverged
+    max_position_uncertainty: f32
+}
+
 impl SensorFusion {
     pub fn new(metric_sender: MetricSender) -> Self {
         SensorFusion { metric_sender }
     }
 
+    // https://www.ceva-ip.com/wp-content/uploads/2019/10/BNO080_085-Datasheet.pdf
+    // Chapter 6.7
+    fn build_filter() -> eskf::ESKF {
+        eskf::Builder::new()
+            .acceleration_variance(SETTINGS.get::<f32>("sensor_fusion.acceleration_variance").unwrap())
+            .rotation_variance(SETTINGS.get::<f32>("sensor_fusion.rotation_variance").unwrap())
+            .acceleration_bias(SETTINGS.get::<f32>("sensor_fusion.acceleration_bias").unwrap())
+            .rotation_bias(SETTINGS.get::<f32>("sensor_fusion.rotation_bias").unwrap())
+            .initial_covariance(SETTINGS.get::<f32>("sensor_fusion.initial_covariance").unwrap())
+            .build()
+    }
+
+    fn reset(filter: &mut eskf::ESKF, state: &mut FusionState, reason: &str) {
+        warn!("Resetting sensor fusion: {}", reason);
+        *filter = Self::build_filter();
+        state.projection = None;
+        state.last_update_ns = None;
+        state.rejections = 0;
+        state.fixes = 0;
+        state.health = FusionHealth::Reset;
+        state.oriented = false;
+    }
+
+    // Normalized innovation squared of a horizontal measurement, the filter uncertainty is a standard deviation
+    fn horizontal_nis(innovation: (f32, f32), uncertainty: &Vector3<f32>, measurement_variance: f32) -> f32 {
+        innovation.0.powi(2) / (uncertainty.x.powi(2) + measurement_variance)
+            + innovation.1.powi(2) / (uncertainty.y.powi(2) + measurement_variance)
+    }
+
+    // Returns false if the measurement should be rejected, resets the filter after too many rejections
+    fn gate_measurement(filter: &mut eskf::ESKF, state: &mut FusionState, config: &FusionConfig, nis: f32, name: &str) -> bool {
+        if state.fixes <= config.warmup_fixes || (nis.is_finite() && nis <= config.nis_threshold) {
+            state.rejections = 0;
+            return true;
+        }
+        state.rejections += 1;
+        state.health = FusionHealth::Degraded;
+        warn!("Rejected {} as outlier (NIS {:.1})", name, nis);
+        if state.rejections >= config.max_rejections {
+            Self::reset(filter, state, "too many rejected measurements");
+        }
+        false
+    }
+
+    fn is_diverged(filter: &eskf::ESKF, config: &FusionConfig) -> bool {
+        let finite = filter.position.coords.iter().chain(filter.velocity.iter()).all(|x| x.is_finite());
+        !finite || filter.position_uncertainty().max() > config.max_position_uncertainty
+    }
+
+    fn observe_gps_position(filter: &mut eskf::ESKF, state: &mut FusionState, config: &FusionConfig, fix: GeoPoint, metric_sender: &MetricSender) {
+        let projection = *state.projection.get_or_insert_with(|| {
+            info!("Sensor fusion anchored at {:?}", fix);
+            LocalProjection::new(fix)
+        });
+        state.fixes += 1;
+        let (east, north) = projection.to_local(&fix);
+        let (east, north) = (east as f32, north as f32);
+        let horizontal_variance = (state.hdop * config.gps_uere).powi(2);
+
+        let nis = Self::horizontal_nis((east - filter.position.x, north - filter.position.y), &filter.position_uncertainty(), horizontal_variance);
+        metric_sender.send_now(MessageId::FusionNis, Value::Float(nis)).unwrap();
+        if !Self::gate_measurement(filter, state, config, nis, "GPS position") {
+            return;
+        }
+
+        // Height is not measured by the fix, keep it loosely at sea level
+        let variance = eskf::ESKF::variance_from_diagonal(Vector3::new(horizontal_variance, horizontal_variance, 100.0));
+        match filter.observe_position(Point3::new(east, north, 0.0), variance) {
+            Ok(_) => state.health = FusionHealth::Ok,
+            Err(err) => {
+                warn!("GPS position update failed: {:?}", err);
+                state.health = FusionHealth::Degraded;
+            }
+        }
+    }
+
+    fn observe_gps_velocity(filter: &mut eskf::ESKF, state: &mut FusionState, config: &FusionConfig, speed_knots: f64, course: f64) {
+        let speed = speed_knots * KNOTS_TO_MS;
+        let course = course.to_radians();
+        let velocity = Vector3::new((speed * course.sin()) as f32, (speed * course.cos()) as f32, 0.0);
+
+        let nis = Self::horizontal_nis((velocity.x - filter.velocity.x, velocity.y - filter.velocity.y), &filter.velocity_uncertainty(), config.gps_velocity_variance);
+        if !Self::gate_measurement(filter, state, config, nis, "GPS velocity") {
+            return;
+        }
+
+        if let Err(err) = filter.observe_velocity(velocity, eskf::ESKF::variance_from_element(config.gps_velocity_variance)) {
+            warn!("GPS velocity update failed: {:?}", err);
+            state.health = FusionHealth::Degraded;
+        }
+    }
+
+    fn send_state(filter: &eskf::ESKF, projection: &LocalProjection, metric_sender: &MetricSender) {
+        let pos_uncertainty = filter.position_uncertainty();
+        let ori_uncertainty = filter.orientation_uncertainty();
+        let velo_uncertainty = filter.velocity_uncertainty();
+        let position = projection.to_geo(filter.position.x as f64, filter.position.y as f64);
+
+        // Position as [lat, lon, up], same layout as GpsPos
+        metric_sender.send_now(MessageId::FusedPosition, Value::Floats(Floats{ values: vec![position.lat as f32, position.lon as f32, filter.position.z] })).unwrap();
+        metric_sender.send_now(MessageId::FusedPositionUncertainty, Value::Floats(Floats{ values: vec![pos_uncertainty.x, pos_uncertainty.y, pos_uncertainty.z] })).unwrap();
+
+        metric_sender.send_now(MessageId::FusedOrientation, Value::Floats(Floats{ values: vec![filter.orientation.i, filter.orientation.j, filter.orientation.k, filter.orientation.w] })).unwrap();
+        metric_sender.send_now(MessageId::FusedOrientationUncertainty, Value::Floats(Floats{ values: vec![ori_uncertainty.x, ori_uncertainty.y, ori_uncertainty.z] })).unwrap();
+
+        // Velocity as [east, north, up] in m/s
+        metric_sender.send_now(MessageId::FusedVelocity, Value::Floats(Floats{ values: vec![filter.velocity.x, filter.velocity.y, filter.velocity.z] })).unwrap();
+        metric_sender.send_now(MessageId::FusedVelocityUncertainty, Value::Floats(Floats{ values: vec![velo_uncertainty.x, velo_uncertainty.y, velo_uncertainty.z] })).unwrap();
+    }
+
     pub async fn run(metric_sender: MetricSender) {
-        let mut metric_receiver = metric_sender.subscribe();
+        let mut metric_receiver = metric_sender.subscribe_as("sensor_fusion");
 
-        // https://www.ceva-ip.com/wp-content/uploads/2019/10/BNO080_085-Datasheet.pdf
-        // Chapter 6.7
-        let mut filter = eskf::Builder::new()
-        // .rotation_variance(0.541052)
-        // .acceleration_variance(0.3)
-        //.initial_covariance(1e-1)
-        .build();
+        let config = FusionConfig {
+            gps_uere: SETTINGS.get::<f32>("sensor_fusion.gps_uere").unwrap(),
+            gps_velocity_variance: SETTINGS.get::<f32>("sensor_fusion.gps_velocity_variance").unwrap(),
+            max_dt: Duration::from_millis(SETTINGS.get::<u64>("sensor_fusion.max_predict_interval").unwrap()),
+            nis_threshold: SETTINGS.get::<f32>("sensor_fusion.nis_threshold").unwrap(),
+            max_rejections: SETTINGS.get::<u32>("sensor_fusion.max_rejections").unwrap(),
+            warmup_fixes: SETTINGS.get::<u32>("sensor_fusion.warmup_fixes").unwrap(),
+            max_position_uncertainty: SETTINGS.get::<f32>("sensor_fusion.max_position_uncertainty").unwrap()
+        };
 
-        let mut last_update_ns: u128 = 0;
+        let mut filter = Self::build_filter();
+        let mut state = FusionState { projection: None, last_update_ns: None, hdop: 1.0, gps_speed_knots: None, rejections: 0, fixes: 0, health: FusionHealth::Reset, oriented: false };
         let mut imu_acceleration = Vector3::new(0.0, 0.0, -9.81);
-        let mut imu_rotation = Vector3::zeros();
-        loop { 
-            let metric: wannsea_types::BoatCoreMessage = metric_receiver.recv().await.unwrap();
-
-            // if let Shape::Circle(_, radius) = my_shape {
-            //     println!("value: {}", radius);
-            // }
-            if metric.id == MessageId::GpsPos {
-                match metric.value.unwrap() {
-                    Value::Floats(floats) => {
-                        filter.observe_position(
-                            Point3::new(floats.values[0], floats.values[1], 0.0),
-                            eskf::ESKF::variance_from_element(0.1))
-                                .expect("Filter update failed");
+        loop {
+            let Some(metric) = metric_receiver.recv().await else { break };
+
+            if metric.id() == MessageId::GpsPos {
+                match metric.value.as_ref() {
+                    Some(Value::Floats(floats)) => {
+                        if let Some(fix) = GeoPoint::from_floats(&floats.values) {
+                            Self::observe_gps_position(&mut filter, &mut state, &config, fix, &metric_sender);
+                        }
+                        metric_sender.send_now(MessageId::FusionHealth, Value::Uint32(state.health as u32)).unwrap();
                     },
                     _ => warn!("GPS unexpected Data format")
                 }
             }
-            else if metric.id == MessageId::ImuAcceleration {
-                match metric.value.unwrap() {
-                    Value::Floats(floats) => {
-                        imu_acceleration[0] = floats.values[0];
-                        imu_acceleration[1] = -floats.values[1];
-                        imu_acceleration[2] = floats.values[2];
+            else if metric.id() == MessageId::GpsHdop {
+                match metric.value {
+                    Some(Value::Double(hdop)) => state.hdop = hdop as f32,
+                    _ => warn!("HDOP unexpected Data format")
+                }
+            }
+            else if metric.id() == MessageId::GpsSpeed {
+                match metric.value {
+                    Some(Value::Double(speed)) => state.gps_speed_knots = Some(speed),
+                    _ => warn!("GPS speed unexpected Data format")
+                }
+            }
+            // Course follows the speed in the same RMC sentence, observe both as a velocity
+            else if metric.id() == MessageId::GpsCourse {
+                match (metric.value, state.gps_speed_knots.take()) {
+                    (Some(Value::Double(course)), Some(speed)) if state.projection.is_some() => {
+                        Self::observe_gps_velocity(&mut filter, &mut state, &config, speed, course);
                     },
-                    _ => warn!("Acceleration unexpected metric format")
+                    (Some(Value::Double(_course)), _) => { /* No speed or no fix yet */ },
+                    _ => warn!("GPS course unexpected Data format")
+                }
+            }
+            else if metric.id() == MessageId::ImuAcceleration {
+                match imu_frame::vector(metric.value.as_ref()) {
+                    Some(acceleration) => imu_acceleration = acceleration,
+                    None => warn!("Acceleration unexpected metric format")
                 }
             }
-            else if metric.id == MessageId::ImuGyro {
-                match metric.value.as_ref().unwrap() {
-                    Value::Floats(floats) => {
-                        imu_rotation[0] = floats.values[0];
-                        imu_rotation[1] = -floats.values[1];
-                        imu_rotation[2] = floats.values[2];
+            // The rotation vector is referenced to east/north/up, it aligns the filter frame with the IMU body
+            else if metric.id() == MessageId::ImuRotation && !state.oriented {
+                match imu_frame::orientation(metric.value.as_ref()) {
+                    Some(orientation) => {
+                        filter.orientation = orientation;
+                        state.oriented = true;
+                        info!("Sensor fusion orientation initialised from IMU rotation");
                     },
-                    _ => warn!("Rotation unexpected metric format")
-                }                
-                filter.predict(imu_acceleration, imu_rotation, Duration::from_nanos((metric.get_ts_ns() - last_update_ns) as u64));
-                last_update_ns = metric.get_ts_ns();
-
-                let pos_uncertainty = filter.position_uncertainty();
-                let ori_uncertainty = filter.orientation_uncertainty();
-                let velo_uncertainty = filter.velocity_uncertainty();
-                metric_sender.send_now(MessageId::FusedPosition, Value::Floats(Floats{ values: vec![filter.position.x, filter.position.y, filter.position.z] })).unwrap();
-                metric_sender.send_now(MessageId::FusedPositionUncertainty, Value::Floats(Floats{ values: vec![pos_uncertainty.x, pos_uncertainty.y, pos_uncertainty.z] })).unwrap();
-
-                metric_sender.send_now(MessageId::FusedOrientation, Value::Floats(Floats{ values: vec![filter.orientation.i, filter.orientation.j, filter.orientation.k, filter.orientation.w] })).unwrap();
-                metric_sender.send_now(MessageId::FusedOrientationUncertainty, Value::Floats(Floats{ values: vec![ori_uncertainty.x, ori_uncertainty.y, ori_uncertainty.z] })).unwrap();
-
-                metric_sender.send_now(MessageId::FusedVelocity, Value::Floats(Floats{ values: vec![filter.velocity.x, filter.velocity.y, filter.velocity.z] })).unwrap();
-                metric_sender.send_now(MessageId::FusedVelocityUncertainty, Value::Floats(Floats{ values: vec![velo_uncertainty.x, velo_uncertainty.y, velo_uncertainty.z] })).unwrap();
+                    None => warn!("Rotation vector unexpected metric format")
+                }
             }
-            
-
-            // Create a default filter, modelling a perfect IMU without drift
-            // Read measurements from IMU
-            // Flip Y because IMU coordinates are left handed
+            else if metric.id() == MessageId::ImuGyro {
+                let Some(imu_rotation) = imu_frame::vector(metric.value.as_ref()) else {
+                    warn!("Rotation unexpected metric format");
+                    continue;
+                };
+                if !state.oriented {
+                    continue;
+                }
 
+                // The first sample only starts the clock
+                let ts_ns = metric.get_ts_ns();
+                let last_update_ns = state.last_update_ns.replace(ts_ns);
+                let dt = match last_update_ns {
+                    Some(last) if ts_ns > last => Duration::from_nanos((ts_ns - last) as u64),
+                    _ => continue
+                };
+                if dt > config.max_dt {
+                    warn!("Skipping IMU gap of {:?}", dt);
+                    continue;
+                }
+                filter.predict(imu_acceleration, imu_rotation, dt);
 
+                if state.projection.is_some() && Self::is_diverged(&filter, &config) {
+                    Self::reset(&mut filter, &mut state, "filter diverged");
+                    metric_sender.send_now(MessageId::FusionHealth, Value::Uint32(state.health as u32)).unwrap();
+                    continue;
+                }
 
-            // Tell the filter what we just measured
-            // Check the new state of the filter
-            // filter.position or filter.velxocity...
-            // ...
-            // After some time we get an observation of the actual state
-            
+                if let Some(projection) = state.projection.as_ref() {
+                    Self::send_state(&filter, projection, &metric_sender);
+                }
+            }
         }
     }
 
     pub fn start(&self) {
         if SETTINGS.get::<bool>("sensor_fusion.enabled").unwrap() {
             info!("Sensor Fusion enabled!");
+            if SETTINGS.get::<u64>("imu.rotation_report_interval").unwrap() == 0 {
+                warn!("Sensor fusion is oriented by the IMU rotation vector, it will not output anything with imu.rotation_report_interval = 0");
+            }
             tokio::spawn(Self::run(self.metric_sender.clone()));
         }
     }
-}
\ No newline at end of file
+}
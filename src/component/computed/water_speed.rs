@@ -0,0 +1,149 @@
+use log::{info, warn};
+use socketcan::EmbeddedFrame;
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::can::{get_can_id, CanReceiver};
+use crate::helper::{bus::BusExt, MetricSender, MetricSenderExt};
+use crate::SETTINGS;
+
+// Speed through water and the water current (set and drift).
+// Speed through water comes from a paddle-wheel log on CAN if one is configured and sending,
+// otherwise from the motor RPM and a simple propeller model. The current is the difference between
+// the velocity over ground (GPS) and the velocity through water along the true heading.
+pub struct WaterSpeed {
+    can_receiver: CanReceiver,
+    metric_sender: MetricSender
+}
+
+struct PropellerModel {
+    motor_pole_pairs: f32,
+    gear_ratio: f32,
+    // Metres per propeller revolution
+    pitch: f32,
+    // 0..1
+    slip: f32
+}
+
+impl PropellerModel {
+    fn speed_knots(&self, erpm: i32) -> f32 {
+        let prop_rps = erpm as f32 / self.motor_pole_pairs / self.gear_ratio / 60.0;
+        (prop_rps * self.pitch * (1.0 - self.slip)).abs() * MS_TO_KNOTS
+    }
+}
+
+const MS_TO_KNOTS: f32 = 3600.0 / 1852.0;
+// A paddle-wheel reading older than this is ignored
+const LOG_TIMEOUT_MS: u128 = 2000;
+
+struct CurrentState {
+    log_speed: Option<(f32, Instant)>,
+    model_speed: Option<f32>,
+    heading: Option<f32>,
+    gps_speed: Option<f32>,
+    // Smoothed current (east, north) in knots
+    current: Option<(f32, f32)>
+}
+
+impl WaterSpeed {
+    pub fn new(can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
+        WaterSpeed { can_receiver, metric_sender }
+    }
+
+    // Paddle-wheel log sends its pulse frequency in Hz as u16
+    async fn listen_log(can_receiver: CanReceiver, metric_sender: MetricSender) {
+        let can_id = SETTINGS.get::<u32>("water_speed.log_can_id").unwrap();
+        let pulses_per_nm = SETTINGS.get::<f32>("water_speed.log_pulses_per_nm").unwrap();
+        let mut receiver = can_receiver.subscribe_as("water_speed_log");
+        loop {
+            let Some(frame) = receiver.recv().await else { break };
+            if get_can_id(frame.id()) != can_id || frame.dlc() < 2 {
+                continue;
+            }
+            let frequency = u16::from_be_bytes(frame.data()[0..2].try_into().unwrap()) as f32;
+            metric_sender.send_now(MessageId::PaddleWheelSpeed, Value::Float(frequency * 3600.0 / pulses_per_nm)).unwrap();
+        }
+    }
+
+    fn water_speed(state: &CurrentState) -> Option<f32> {
+        match state.log_speed {
+            Some((speed, ts)) if ts.elapsed().as_millis() < LOG_TIMEOUT_MS => Some(speed),
+            _ => state.model_speed
+        }
+    }
+
+    fn update_current(state: &mut CurrentState, course: f32, smoothing: f32, metric_sender: &MetricSender) {
+        let (stw, heading, sog) = match (Self::water_speed(state), state.heading, state.gps_speed) {
+            (Some(stw), Some(heading), Some(sog)) => (stw, heading.to_radians(), sog),
+            _ => return
+        };
+        let course = course.to_radians();
+
+        let ground = (sog * course.sin(), sog * course.cos());
+        let water = (stw * heading.sin(), stw * heading.cos());
+        let measured = (ground.0 - water.0, ground.1 - water.1);
+        let current = match state.current {
+            Some(last) => (last.0 + smoothing * (measured.0 - last.0), last.1 + smoothing * (measured.1 - last.1)),
+            None => measured
+        };
+        state.current = Some(current);
+
+        // Set is the direction the current flows to
+        let set = (current.0.atan2(current.1).to_degrees() + 360.0) % 360.0;
+        let drift = (current.0.powi(2) + current.1.powi(2)).sqrt();
+        metric_sender.send_now(MessageId::CurrentSet, Value::Float(set)).unwrap();
+        metric_sender.send_now(MessageId::CurrentDrift, Value::Float(drift)).unwrap();
+    }
+
+    async fn run(metric_sender: MetricSender) {
+        let model = PropellerModel {
+            motor_pole_pairs: SETTINGS.get::<f32>("water_speed.motor_pole_pairs").unwrap(),
+            gear_ratio: SETTINGS.get::<f32>("water_speed.gear_ratio").unwrap(),
+            pitch: SETTINGS.get::<f32>("water_speed.propeller_pitch").unwrap(),
+            slip: SETTINGS.get::<f32>("water_speed.propeller_slip").unwrap()
+        };
+        // Weight of a new measurement in the smoothed current, 1 disables smoothing
+        let smoothing = SETTINGS.get::<f32>("water_speed.current_smoothing").unwrap();
+
+        let mut state = CurrentState { log_speed: None, model_speed: None, heading: None, gps_speed: None, current: None };
+        let mut receiver = metric_sender.subscribe_as("water_speed");
+        loop {
+            let Some(metric) = receiver.recv().await else { break };
+            let id = metric.id();
+            if !matches!(id, MessageId::PaddleWheelSpeed | MessageId::EscRpm | MessageId::AttitudeHeadingTrue | MessageId::GpsSpeed | MessageId::GpsCourse) {
+                continue;
+            }
+
+            match (id, metric.value) {
+                (MessageId::PaddleWheelSpeed, Some(Value::Float(speed))) => {
+                    state.log_speed = Some((speed, Instant::now()));
+                    metric_sender.send_now(MessageId::WaterSpeed, Value::Float(speed)).unwrap();
+                },
+                (MessageId::EscRpm, Some(Value::Int32(erpm))) => {
+                    let speed = model.speed_knots(erpm);
+                    state.model_speed = Some(speed);
+                    if state.log_speed.map_or(true, |(_speed, ts)| ts.elapsed().as_millis() >= LOG_TIMEOUT_MS) {
+                        metric_sender.send_now(MessageId::WaterSpeed, Value::Float(speed)).unwrap();
+                    }
+                },
+                (MessageId::AttitudeHeadingTrue, Some(Value::Float(heading))) => state.heading = Some(heading),
+                (MessageId::GpsSpeed, Some(Value::Double(speed))) => state.gps_speed = Some(speed as f32),
+                // Course follows the speed in the same RMC sentence
+                (MessageId::GpsCourse, Some(Value::Double(course))) => Self::update_current(&mut state, course as f32, smoothing, &metric_sender),
+                _ => warn!("Unexpected {} format", id.as_str_name())
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("water_speed.enabled").unwrap() {
+            info!("Computed Water Speed enabled!");
+
+            if SETTINGS.get::<u32>("water_speed.log_can_id").unwrap() != 0 {
+                tokio::spawn(Self::listen_log(self.can_receiver.clone(), self.metric_sender.clone()));
+            }
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
@@ -41,6 +41,9 @@ impl GPS {
                 })) {
                     sender.send_now(MessageId::GpsPos, Value::Floats(wannsea_types::Floats{ values: gps_pos })).unwrap();
                 } 
+                if let Some(hdop) = gga.hdop {
+                    sender.send_now(MessageId::GpsHdop, Value::Double(hdop)).unwrap();
+                }
             },
             ParsedMessage::Rmc(rmc) => {
                 // debug!("rmc: {:?}", rmc);
@@ -51,6 +54,10 @@ impl GPS {
                 if let Some(course) = rmc.bearing {
                     sender.send_now(MessageId::GpsCourse, Value::Double(course)).unwrap();
                 }
+                // Only reported by some receivers, east positive
+                if let Some(variation) = rmc.variation {
+                    sender.send_now(MessageId::GpsMagneticVariation, Value::Double(variation)).unwrap();
+                }
              },
             ParsedMessage::Gsa(_gsa) => {
                 /* Ignore */
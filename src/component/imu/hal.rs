@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use bno085::{bno_driver::{BnoDriver, DriverError}, bno_packet::BnoPacket, interface::SensorInterface};
+use chrono::{DateTime, Utc};
+use rppal::gpio::{Gpio, InputPin, Trigger};
+
+// Hardware the IMU reader depends on, kept behind traits so the reader can run against a simulated sensor
+
+// Data ready signal of the sensor
+pub trait InterruptSource {
+    // Blocks until the next interrupt and returns the time it fired, None on timeout
+    fn wait(&mut self, timeout: Duration) -> Result<Option<DateTime<Utc>>, String>;
+}
+
+// Packet level access to the sensor
+pub trait ImuBus {
+    fn setup(&mut self);
+    fn soft_reset(&mut self) -> Result<(), DriverError>;
+    fn receive_packet(&mut self) -> Result<BnoPacket, DriverError>;
+    fn enable_report(&mut self, report_id: u8, interval: u16, sensor_specific: u16) -> Result<(), DriverError>;
+    fn save_dcd(&mut self) -> Result<(), DriverError>;
+}
+
+impl<I: SensorInterface> ImuBus for BnoDriver<I> {
+    fn setup(&mut self) {
+        BnoDriver::setup(self)
+    }
+
+    fn soft_reset(&mut self) -> Result<(), DriverError> {
+        BnoDriver::soft_reset(self)
+    }
+
+    fn receive_packet(&mut self) -> Result<BnoPacket, DriverError> {
+        BnoDriver::receive_packet(self)
+    }
+
+    fn enable_report(&mut self, report_id: u8, interval: u16, sensor_specific: u16) -> Result<(), DriverError> {
+        BnoDriver::enable_report(self, report_id, interval, sensor_specific)
+    }
+
+    fn save_dcd(&mut self) -> Result<(), DriverError> {
+        BnoDriver::save_dcd(self)
+    }
+}
+
+// BNO085 INT pin, active low until the host has read the pending data
+pub struct GpioInterrupt {
+    pin: InputPin
+}
+
+impl GpioInterrupt {
+    pub fn new(pin: u8) -> Result<Self, rppal::gpio::Error> {
+        let mut pin = Gpio::new()?.get(pin)?.into_input_pullup();
+        pin.set_interrupt(Trigger::FallingEdge)?;
+        Ok(GpioInterrupt { pin })
+    }
+}
+
+impl InterruptSource for GpioInterrupt {
+    fn wait(&mut self, timeout: Duration) -> Result<Option<DateTime<Utc>>, String> {
+        // Keep edges that fired while the last data was read, otherwise the next interrupt is lost
+        match self.pin.poll_interrupt(false, Some(timeout)) {
+            Ok(Some(_level)) => Ok(Some(Utc::now())),
+            Ok(None) => Ok(None),
+            Err(err) => Err(err.to_string())
+        }
+    }
+}
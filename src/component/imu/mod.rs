@@ -1,121 +1,311 @@
+mod hal;
+
+use std::{collections::HashMap, thread, time::{Duration, Instant}};
+
 use crate::{
-    helper::{MetricSender, MetricSenderExt},
+    helper::{bus::{BusExt, BusReceiver}, MetricSender, MetricSenderExt},
     SETTINGS,
 };
-use bno085::{bno_constants::SENSOR_REPORTID_LINEAR_ACCEL, bno_driver::BnoDriver, bno_packet::ChannelCommandData};
 use bno085::{
     bno_constants::{
-        SENSOR_REPORTID_ACCEL, SENSOR_REPORTID_GYRO_CALIBRATED, SENSOR_REPORTID_ROTATION_VECTOR,
+        SENSOR_REPORTID_ACCEL, SENSOR_REPORTID_GAME_ROTATION_VECTOR, SENSOR_REPORTID_GYRO_CALIBRATED,
+        SENSOR_REPORTID_LINEAR_ACCEL, SENSOR_REPORTID_MAGNETIC_FIELD_CALIBRATED, SENSOR_REPORTID_ROTATION_VECTOR,
     },
-    bno_packet::{BnoPacket::ChannelExec, ChannelExecutableData},
+    bno_driver::{BnoDriver, DriverError},
+    bno_packet::{BnoPacket, ChannelExecutableData, SensorReportData},
     interface::i2c::I2CInterface,
 };
-use log::{info, warn};
-use tokio::time::{sleep, Duration};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
 use wannsea_types::boat_core_message::Value;
-use wannsea_types::{Floats, MessageId};
+use wannsea_types::{BoatCoreMessage, Floats, MessageId, StringFloatMap};
+
+use self::hal::{GpioInterrupt, ImuBus, InterruptSource};
+
 pub struct IMU {
     metric_sender: MetricSender,
 }
 
-impl IMU {
-    pub fn new(metric_sender: MetricSender) -> Self {
-        IMU { metric_sender }
+// Reads the sensor whenever its INT pin signals new data.
+// Runs on its own thread as both the interrupt wait and the I2C transfers block.
+struct ImuReader<B: ImuBus, S: InterruptSource> {
+    bus: B,
+    interrupt: S,
+    metric_sender: MetricSender,
+    command_receiver: BusReceiver<BoatCoreMessage>,
+    enabled_reports: Vec<(u8, u16)>,
+    interrupt_timeout: Duration,
+    // Accuracy status (0 = unreliable .. 3 = high) of the last report of each sensor
+    calibration_status: HashMap<String, f32>,
+    // Published when an accuracy changed, otherwise repeated every CALIBRATION_STATUS_INTERVAL
+    calibration_status_sent: Option<Instant>,
+    calibration_status_changed: bool,
+}
+
+const CALIBRATION_STATUS_INTERVAL: Duration = Duration::from_secs(1);
+
+impl<B: ImuBus, S: InterruptSource> ImuReader<B, S> {
+    fn new(bus: B, interrupt: S, metric_sender: MetricSender, enabled_reports: Vec<(u8, u16)>, interrupt_timeout: Duration) -> Self {
+        let command_receiver = metric_sender.subscribe_as("imu");
+        ImuReader { bus, interrupt, metric_sender, command_receiver, enabled_reports, interrupt_timeout, calibration_status: HashMap::new(), calibration_status_sent: None, calibration_status_changed: false }
     }
 
-    pub async fn run_thread(metric_sender: MetricSender) {
-        let rpi_interface = rppal::i2c::I2c::new().unwrap();
-        let interface = I2CInterface::new(rpi_interface);
+    fn enable_reports(&mut self) {
+        for (report_id, interval) in &self.enabled_reports {
+            if let Err(err) = self.bus.enable_report(*report_id, *interval, interval - 1) {
+                warn!("Could not enable BNO report {:#X}: {:?}", report_id, err);
+            }
+        }
+    }
 
-        let accel_interval = SETTINGS.get::<u16>("imu.accel_report_interval").unwrap();
-        let gyro_interval = SETTINGS.get::<u16>("imu.gyro_report_interval").unwrap();
-        let rotation_interval = SETTINGS.get::<u16>("imu.rotation_report_interval").unwrap();
+    // Handle commands sent on the metric bus between two interrupts
+    fn handle_commands(&mut self) {
+        while let Some(metric) = self.command_receiver.try_recv() {
+            if metric.id() == MessageId::ImuSaveCalibration {
+                // Stores the dynamic calibration data (DCD) in the sensor's flash so it survives a reset
+                match self.bus.save_dcd() {
+                    Ok(_) => info!("Saved BNO085 calibration to flash"),
+                    Err(err) => warn!("Could not save BNO085 calibration: {:?}", err)
+                }
+            }
+        }
+    }
 
-        let query_interval = SETTINGS.get::<u64>("imu.metric_update_interval").unwrap();
+    fn send_report(&mut self, id: MessageId, accuracy_key: Option<&str>, accuracy: u8, values: Vec<f32>, ts: DateTime<Utc>) {
+        if let Some(key) = accuracy_key {
+            if self.calibration_status.insert(key.to_string(), accuracy as f32) != Some(accuracy as f32) {
+                self.calibration_status_changed = true;
+            }
+        }
+        self.metric_sender.send_at(id, Value::Floats(Floats{ values }), ts).unwrap();
+    }
 
-        let mut driver = BnoDriver::new(interface);
-        driver.setup();
-        driver.soft_reset().unwrap();
+    fn send_calibration_status(&mut self, ts: DateTime<Utc>) {
+        let due = self.calibration_status_sent.is_none_or(|sent| sent.elapsed() >= CALIBRATION_STATUS_INTERVAL);
+        if !self.calibration_status_changed && !due {
+            return;
+        }
+        self.metric_sender.send_at(MessageId::ImuCalibrationStatus, Value::StringFloatMap(StringFloatMap { items: self.calibration_status.clone() }), ts).unwrap();
+        self.calibration_status_sent = Some(Instant::now());
+        self.calibration_status_changed = false;
+    }
+
+    fn handle_packet(&mut self, packet: BnoPacket, ts: DateTime<Utc>) {
+        match packet {
+            BnoPacket::ChannelExec(ce) => match ce {
+                ChannelExecutableData::ResetComplete => {
+                    info!("Reset Complete, enabling Reports!");
+                    // Enable reports after reset
+                    self.enable_reports();
+                }
+                ChannelExecutableData::Unknown(ced) => {
+                    println!("CED {:?}", ced);
+                }
+            },
+            BnoPacket::SensorReports(reports) => {
+                for report in reports {
+                    match report {
+                        SensorReportData::Acceleration(d) => self.send_report(MessageId::ImuAcceleration, Some("accel"), d.get_accuracy(), d.get_vec(), ts),
+                        SensorReportData::Rotation(d) => self.send_report(MessageId::ImuRotation, Some("rotation"), d.get_accuracy(), d.get_vec(), ts),
+                        SensorReportData::GameRotation(d) => self.send_report(MessageId::ImuGameRotation, None, d.get_accuracy(), d.get_vec(), ts),
+                        SensorReportData::GyroCalibrated(d) => self.send_report(MessageId::ImuGyro, Some("gyro"), d.get_accuracy(), d.get_vec(), ts),
+                        SensorReportData::LinearAcceleration(d) => self.send_report(MessageId::ImuLinearAcceleration, None, d.get_accuracy(), d.get_vec(), ts),
+                        SensorReportData::MagneticField(d) => self.send_report(MessageId::ImuMagneticField, Some("mag"), d.get_accuracy(), d.get_vec(), ts),
+                        d => warn!("Unknown Sensor Data {:?}", d),
+                    };
+                }
+                self.send_calibration_status(ts);
+            }
+            d => {
+                println!("CED: {:?}", d);
+            }
+        }
+    }
+
+    // Read until the sensor has no more data, all reports get the timestamp of the interrupt
+    fn read_pending(&mut self, ts: DateTime<Utc>) {
+        loop {
+            match self.bus.receive_packet() {
+                Ok(packet) => self.handle_packet(packet, ts),
+                Err(DriverError::NoDataAvailable) => break,
+                Err(e) => {
+                    warn!("BNO Driver Error {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn run(&mut self) {
+        self.bus.setup();
+        if let Err(err) = self.bus.soft_reset() {
+            warn!("BNO soft reset failed: {:?}", err);
+        }
 
         loop {
-            match driver.receive_packet() {
-                Ok(res) => match res {
-                    ChannelExec(ce) => match ce {
-                        ChannelExecutableData::ResetComplete => {
-                            info!("Reset Complete, enabling Reports!");
-                            // Enable reports after reset
-                            driver
-                                .enable_report(
-                                    SENSOR_REPORTID_ACCEL,
-                                    accel_interval,
-                                    accel_interval - 1,
-                                )
-                                .unwrap();
-                            // driver
-                            //     .enable_report(
-                            //         SENSOR_REPORTID_ROTATION_VECTOR,
-                            //         rotation_interval,
-                            //         rotation_interval - 1,
-                            //     )
-                            //     .unwrap();
-                            driver
-                                .enable_report(
-                                    SENSOR_REPORTID_GYRO_CALIBRATED,
-                                    gyro_interval,
-                                    gyro_interval - 1,
-                                )
-                                .unwrap();
-                        }
-                        ChannelExecutableData::Unknown(ced) => { 
-                            println!("CED {:?}", ced);
-                        }
-                    },
-                    bno085::bno_packet::BnoPacket::SensorReports(reports) => {
-                        for report in reports {
-                            match report {
-                                bno085::bno_packet::SensorReportData::Acceleration(d) => metric_sender.send_now(MessageId::ImuAcceleration, Value::Floats(Floats{ values: d.get_vec() })).unwrap(),
-                                bno085::bno_packet::SensorReportData::Rotation(d) => metric_sender.send_now(MessageId::ImuRotation, Value::Floats(Floats{ values: d.get_vec() })).unwrap(),
-                                bno085::bno_packet::SensorReportData::GyroCalibrated(d) => metric_sender.send_now(MessageId::ImuGyro, Value::Floats(Floats{ values: d.get_vec() })).unwrap(),
-                                d => {
-                                    warn!("Unknown Sensor Data {:?}", d);
-                                    0 as usize
-                                },
-                            };
-                        }
-                    }
-                    d => { 
-                        println!("CED: {:?}", d);
-                    }
-                },
-                Err(err) => {
-                    match err {
-                        bno085::bno_driver::DriverError::NoDataAvailable => { /* Nothing to do, can happen due to sleep/clock drift */ },
-                        e => {
-                            warn!("BNO Driver Error {:?}", e);
-                        }
-                    }
-                },
+            self.step();
+        }
+    }
+
+    fn step(&mut self) {
+        self.handle_commands();
+
+        match self.interrupt.wait(self.interrupt_timeout) {
+            Ok(Some(ts)) => self.read_pending(ts),
+            Ok(None) => {
+                // Missed edge or sensor stalled, poll once so we do not wait forever
+                debug!("No IMU interrupt within {:?}, polling", self.interrupt_timeout);
+                self.read_pending(Utc::now());
+            },
+            Err(err) => {
+                warn!("Waiting for IMU interrupt failed: {}", err);
+                thread::sleep(self.interrupt_timeout);
             }
-            sleep(Duration::from_millis(query_interval)).await;
         }
+    }
+}
+
+impl IMU {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        IMU { metric_sender }
+    }
 
-        // loop {
-        //     let _msg_count = imu_driver.handle_all_messages(&mut delay_source, 1);
-        //     if _msg_count > 0 {
-        //         metric_sender.send_now(MessageId::Rotation, Value::Floats(Floats{ values: imu_driver.rotation_quaternion().unwrap().to_vec() })).unwrap();
-        //         metric_sender.send_now(MessageId::Acceleration, Value::Floats(Floats{ values: imu_driver.accel().unwrap().to_vec() })).unwrap();
-        //         metric_sender.send_now(MessageId::Gyro, Value::Floats(Floats{ values: imu_driver.gyro().unwrap().to_vec() })).unwrap();
+    // Report id and interval in ms for every report enabled in the config, an interval of 0 disables the report
+    fn configured_reports() -> Vec<(u8, u16)> {
+        [
+            (SENSOR_REPORTID_ACCEL, "imu.accel_report_interval"),
+            (SENSOR_REPORTID_GYRO_CALIBRATED, "imu.gyro_report_interval"),
+            (SENSOR_REPORTID_ROTATION_VECTOR, "imu.rotation_report_interval"),
+            (SENSOR_REPORTID_GAME_ROTATION_VECTOR, "imu.game_rotation_report_interval"),
+            (SENSOR_REPORTID_LINEAR_ACCEL, "imu.linear_accel_report_interval"),
+            (SENSOR_REPORTID_MAGNETIC_FIELD_CALIBRATED, "imu.magnetometer_report_interval"),
+        ]
+        .iter()
+        .map(|(report_id, key)| (*report_id, SETTINGS.get::<u16>(key).unwrap()))
+        .filter(|(_report_id, interval)| *interval > 0)
+        .collect()
+    }
 
-        //     }
-        //     tokio::time::sleep(tokio::time::Duration::from_millis(SETTINGS.get::<u64>("imu.metric_update_interval").unwrap())).await;
-        // }
+    fn run_thread(metric_sender: MetricSender) {
+        let rpi_interface = match rppal::i2c::I2c::new() {
+            Ok(i2c) => i2c,
+            Err(err) => {
+                error!("Could not open IMU I2C bus: {:?}. Exiting thread!", err);
+                return;
+            }
+        };
+        let driver = BnoDriver::new(I2CInterface::new(rpi_interface));
+
+        let interrupt_pin = SETTINGS.get::<u8>("imu.interrupt_pin").unwrap();
+        let interrupt = match GpioInterrupt::new(interrupt_pin) {
+            Ok(interrupt) => interrupt,
+            Err(err) => {
+                error!("Could not set up IMU interrupt on GPIO {}: {}. Exiting thread!", interrupt_pin, err);
+                return;
+            }
+        };
+        let interrupt_timeout = Duration::from_millis(SETTINGS.get::<u64>("imu.interrupt_timeout").unwrap());
+
+        ImuReader::new(driver, interrupt, metric_sender, Self::configured_reports(), interrupt_timeout).run();
     }
 
     pub fn start(&self) {
         if SETTINGS.get::<bool>("imu.enabled").unwrap() == true {
             info!("IMU enabled!");
 
-            tokio::spawn(Self::run_thread(self.metric_sender.clone()));
+            let metric_sender = self.metric_sender.clone();
+            thread::Builder::new()
+                .name("imu".to_string())
+                .spawn(move || Self::run_thread(metric_sender))
+                .unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use tokio::sync::broadcast;
+
+    use super::*;
+
+    // Sensor that hands out a fixed list of packets
+    #[derive(Default)]
+    struct SimulatedBus {
+        packets: VecDeque<BnoPacket>,
+        enabled_reports: Vec<(u8, u16)>,
+        saved_dcd: usize
+    }
+
+    impl ImuBus for SimulatedBus {
+        fn setup(&mut self) {}
+
+        fn soft_reset(&mut self) -> Result<(), DriverError> {
+            self.packets.push_back(BnoPacket::ChannelExec(ChannelExecutableData::ResetComplete));
+            Ok(())
+        }
+
+        fn receive_packet(&mut self) -> Result<BnoPacket, DriverError> {
+            self.packets.pop_front().ok_or(DriverError::NoDataAvailable)
         }
+
+        fn enable_report(&mut self, report_id: u8, interval: u16, _sensor_specific: u16) -> Result<(), DriverError> {
+            self.enabled_reports.push((report_id, interval));
+            Ok(())
+        }
+
+        fn save_dcd(&mut self) -> Result<(), DriverError> {
+            self.saved_dcd += 1;
+            Ok(())
+        }
+    }
+
+    // Fires the queued interrupts, times out once they are used up
+    #[derive(Default)]
+    struct SimulatedInterrupt {
+        interrupts: VecDeque<DateTime<Utc>>
+    }
+
+    impl InterruptSource for SimulatedInterrupt {
+        fn wait(&mut self, _timeout: Duration) -> Result<Option<DateTime<Utc>>, String> {
+            Ok(self.interrupts.pop_front())
+        }
+    }
+
+    fn reader(interrupts: usize) -> (ImuReader<SimulatedBus, SimulatedInterrupt>, MetricSender) {
+        let (metric_sender, _receiver) = broadcast::channel(16);
+        let interrupt = SimulatedInterrupt { interrupts: (0..interrupts).map(|_| Utc::now()).collect() };
+        let reports = vec![(SENSOR_REPORTID_ACCEL, 10), (SENSOR_REPORTID_ROTATION_VECTOR, 20)];
+        let reader = ImuReader::new(SimulatedBus::default(), interrupt, metric_sender.clone(), reports, Duration::from_millis(100));
+        (reader, metric_sender)
+    }
+
+    #[test]
+    fn enables_reports_after_reset_on_interrupt() {
+        let (mut reader, _metric_sender) = reader(1);
+        reader.bus.soft_reset().unwrap();
+        reader.step();
+
+        assert_eq!(reader.bus.enabled_reports, vec![(SENSOR_REPORTID_ACCEL, 10), (SENSOR_REPORTID_ROTATION_VECTOR, 20)]);
+        assert!(reader.bus.packets.is_empty());
+    }
+
+    #[test]
+    fn polls_sensor_on_interrupt_timeout() {
+        let (mut reader, _metric_sender) = reader(0);
+        reader.bus.soft_reset().unwrap();
+        reader.step();
+
+        assert_eq!(reader.bus.enabled_reports.len(), 2);
+    }
+
+    #[test]
+    fn saves_calibration_on_command() {
+        let (mut reader, metric_sender) = reader(0);
+        metric_sender.send_now(MessageId::ImuSaveCalibration, Value::Uint32(1)).unwrap();
+        reader.step();
+
+        assert_eq!(reader.bus.saved_dcd, 1);
     }
 }
@@ -5,4 +5,7 @@ pub mod lte;
 pub mod system_stats;
 pub mod computed;
 pub mod imu;
-pub mod vesc;
\ No newline at end of file
+pub mod vesc;
+pub mod navigation;
+pub mod throttle;
+pub mod protection;
\ No newline at end of file
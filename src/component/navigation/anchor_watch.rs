@@ -0,0 +1,129 @@
+use log::{info, warn};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, Floats, MessageId};
+
+use crate::{helper::{bus::BusExt, geo::GeoPoint, MetricSender, MetricSenderExt}, SETTINGS};
+
+// Anchor watch and man overboard marker.
+// Both are controlled by command messages on the metric bus (e.g. sent through the WebSocket server):
+// AnchorWatchCommand / MobCommand with Uint32(1) store the current position, Uint32(0) clears it.
+pub struct AnchorWatch {
+    metric_sender: MetricSender
+}
+
+struct WatchState {
+    position: Option<GeoPoint>,
+    anchor: Option<GeoPoint>,
+    anchor_alarm: bool,
+    mob: Option<GeoPoint>
+}
+
+impl AnchorWatch {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        AnchorWatch { metric_sender }
+    }
+
+    fn is_set_command(metric: &BoatCoreMessage) -> Option<bool> {
+        match metric.value.as_ref() {
+            Some(Value::Uint32(x)) => Some(*x != 0),
+            _ => None
+        }
+    }
+
+    fn handle_command(state: &mut WatchState, metric: &BoatCoreMessage, metric_sender: &MetricSender) {
+        let set = match Self::is_set_command(metric) {
+            Some(set) => set,
+            None => {
+                warn!("Unexpected {} command format", metric.id().as_str_name());
+                return;
+            }
+        };
+        if set && state.position.is_none() {
+            warn!("No position available for {}", metric.id().as_str_name());
+            return;
+        }
+
+        if metric.id() == MessageId::AnchorWatchCommand {
+            state.anchor = if set { state.position } else { None };
+            state.anchor_alarm = false;
+            match state.anchor {
+                Some(anchor) => {
+                    info!("Anchor watch set at {:?}", anchor);
+                    metric_sender.send_now(MessageId::AnchorPos, Value::Floats(Floats { values: vec![anchor.lat as f32, anchor.lon as f32] })).unwrap();
+                },
+                None => info!("Anchor watch cleared")
+            }
+        }
+        else if metric.id() == MessageId::MobCommand {
+            state.mob = if set { state.position } else { None };
+            match state.mob {
+                Some(mob) => {
+                    warn!("Man overboard marked at {:?}", mob);
+                    metric_sender.send_now(MessageId::MobPos, Value::Floats(Floats { values: vec![mob.lat as f32, mob.lon as f32] })).unwrap();
+                },
+                None => info!("Man overboard marker cleared")
+            }
+        }
+    }
+
+    fn send_position_metrics(state: &mut WatchState, radius: f64, metric_sender: &MetricSender) {
+        let position = match state.position {
+            Some(position) => position,
+            None => return
+        };
+
+        if let Some(anchor) = state.anchor {
+            let distance = position.distance_to(&anchor);
+            let alarm = distance > radius;
+            if alarm && !state.anchor_alarm {
+                warn!("Anchor alarm! Drifted {:.1} m from the anchor position", distance);
+            }
+            state.anchor_alarm = alarm;
+            metric_sender.send_now(MessageId::AnchorDistance, Value::Float(distance as f32)).unwrap();
+            metric_sender.send_now(MessageId::AnchorAlarm, Value::Uint32(alarm as u32)).unwrap();
+        }
+
+        if let Some(mob) = state.mob {
+            metric_sender.send_now(MessageId::MobDistance, Value::Float(position.distance_to(&mob) as f32)).unwrap();
+            metric_sender.send_now(MessageId::MobBearing, Value::Float(position.bearing_to(&mob) as f32)).unwrap();
+        }
+    }
+
+    pub async fn run(metric_sender: MetricSender) {
+        let radius = SETTINGS.get::<f64>("anchor_watch.radius").unwrap();
+        let source_name = SETTINGS.get::<String>("anchor_watch.position_source").unwrap();
+        let position_source = match MessageId::from_str_name(&source_name) {
+            Some(id) => id,
+            None => {
+                warn!("Unknown anchor watch position source {}, using GPS_POS", source_name);
+                MessageId::GpsPos
+            }
+        };
+
+        let mut state = WatchState { position: None, anchor: None, anchor_alarm: false, mob: None };
+        let mut metric_receiver = metric_sender.subscribe_as("anchor_watch");
+        loop {
+            let Some(metric) = metric_receiver.recv().await else { break };
+
+            if metric.id() == position_source {
+                match metric.value.as_ref().unwrap() {
+                    Value::Floats(floats) => {
+                        state.position = GeoPoint::from_floats(&floats.values);
+                        Self::send_position_metrics(&mut state, radius, &metric_sender);
+                    },
+                    _ => warn!("Position unexpected Data format")
+                }
+            }
+            else if metric.id() == MessageId::AnchorWatchCommand || metric.id() == MessageId::MobCommand {
+                Self::handle_command(&mut state, &metric, &metric_sender);
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("anchor_watch.enabled").unwrap() {
+            info!("Anchor Watch enabled!");
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use serde::Deserialize;
+
+use crate::helper::geo::GeoPoint;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Mark {
+    pub name: String,
+    // [lat, lon]
+    pub position: [f64; 2]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct StartLine {
+    pub pin: [f64; 2],
+    pub committee: [f64; 2]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Geofence {
+    pub name: String,
+    pub points: Vec<[f64; 2]>
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Course {
+    // Radius around a mark in which it counts as rounded
+    pub mark_radius: f64,
+    pub start_line: Option<StartLine>,
+    #[serde(default)]
+    pub marks: Vec<Mark>,
+    #[serde(default)]
+    pub geofences: Vec<Geofence>
+}
+
+impl Course {
+    pub fn load(path: &str) -> Result<Self, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::with_name(path))
+            .build()?
+            .try_deserialize::<Course>()
+    }
+
+    pub fn mark_position(&self, idx: usize) -> GeoPoint {
+        let pos = self.marks[idx].position;
+        GeoPoint::new(pos[0], pos[1])
+    }
+
+    pub fn start_line_points(&self) -> Option<(GeoPoint, GeoPoint)> {
+        self.start_line.as_ref().map(|line| (
+            GeoPoint::new(line.pin[0], line.pin[1]),
+            GeoPoint::new(line.committee[0], line.committee[1])
+        ))
+    }
+
+    pub fn geofence_polygons(&self) -> Vec<Vec<GeoPoint>> {
+        self.geofences.iter()
+            .map(|fence| fence.points.iter().map(|p| GeoPoint::new(p[0], p[1])).collect())
+            .collect()
+    }
+}
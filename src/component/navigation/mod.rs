@@ -0,0 +1,159 @@
+pub mod course;
+pub mod anchor_watch;
+
+use log::{error, info, warn};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::{helper::{bus::BusExt, geo::{polygon_contains, segments_intersect, GeoPoint}, MetricSender, MetricSenderExt}, SETTINGS};
+
+use self::course::Course;
+
+pub struct Navigation {
+    metric_sender: MetricSender
+}
+
+// Progress of the boat along the course.
+// With a start line the course begins when crossing it and every lap ends by crossing it again,
+// without one the first fix is the start and a lap ends at the last mark.
+struct CourseProgress {
+    course: Course,
+    geofences: Vec<Vec<GeoPoint>>,
+    started: bool,
+    lap: u32,
+    leg: usize,
+    leg_start: Option<GeoPoint>,
+    last_pos: Option<GeoPoint>,
+    outside_geofence: bool
+}
+
+impl CourseProgress {
+    fn new(course: Course) -> Self {
+        let geofences = course.geofence_polygons();
+        let started = course.start_line.is_none();
+        CourseProgress { course, geofences, started, lap: if started { 1 } else { 0 }, leg: 0, leg_start: None, last_pos: None, outside_geofence: false }
+    }
+
+    fn crossed_start_line(&self, pos: &GeoPoint) -> bool {
+        match (self.course.start_line_points(), self.last_pos) {
+            (Some((pin, committee)), Some(last_pos)) => segments_intersect(&last_pos, pos, &pin, &committee),
+            _ => false
+        }
+    }
+
+    // Next point to sail to, either a mark or the middle of the start line
+    fn target(&self) -> Option<GeoPoint> {
+        if self.started && self.leg < self.course.marks.len() {
+            return Some(self.course.mark_position(self.leg));
+        }
+        self.course.start_line_points().map(|(pin, committee)| pin.midpoint(&committee))
+    }
+
+    fn update(&mut self, pos: GeoPoint) {
+        if self.leg_start.is_none() {
+            self.leg_start = Some(pos);
+        }
+
+        if self.course.start_line.is_some() && (!self.started || self.leg >= self.course.marks.len()) {
+            if self.crossed_start_line(&pos) {
+                self.started = true;
+                self.lap += 1;
+                self.leg = 0;
+                self.leg_start = Some(pos);
+                info!("Crossed start line, starting lap {}", self.lap);
+            }
+        }
+        else if self.started && self.leg < self.course.marks.len() {
+            let mark = self.course.mark_position(self.leg);
+            if pos.distance_to(&mark) <= self.course.mark_radius {
+                info!("Rounded mark {}", self.course.marks[self.leg].name);
+                self.leg += 1;
+                self.leg_start = Some(mark);
+                if self.leg >= self.course.marks.len() && self.course.start_line.is_none() {
+                    self.lap += 1;
+                    self.leg = 0;
+                    info!("Finished lap, starting lap {}", self.lap);
+                }
+            }
+        }
+
+        self.last_pos = Some(pos);
+    }
+
+    fn is_inside_geofence(&self, pos: &GeoPoint) -> bool {
+        self.geofences.iter().any(|fence| polygon_contains(fence, pos))
+    }
+}
+
+impl Navigation {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        Navigation { metric_sender }
+    }
+
+    fn send_progress(progress: &mut CourseProgress, pos: GeoPoint, metric_sender: &MetricSender) {
+        progress.update(pos);
+
+        if let Some(target) = progress.target() {
+            metric_sender.send_now(MessageId::NavMarkDistance, Value::Float(pos.distance_to(&target) as f32)).unwrap();
+            metric_sender.send_now(MessageId::NavMarkBearing, Value::Float(pos.bearing_to(&target) as f32)).unwrap();
+
+            if let Some(leg_start) = progress.leg_start {
+                let leg_length = leg_start.distance_to(&target);
+                if leg_length > 0.0 {
+                    let along_track = pos.along_track_distance(&leg_start, &target);
+                    metric_sender.send_now(MessageId::NavCrossTrackError, Value::Float(pos.cross_track_distance(&leg_start, &target) as f32)).unwrap();
+                    metric_sender.send_now(MessageId::NavLegProgress, Value::Float((along_track / leg_length).clamp(0.0, 1.0) as f32)).unwrap();
+                }
+            }
+        }
+        metric_sender.send_now(MessageId::NavLeg, Value::Uint32(progress.leg as u32)).unwrap();
+        metric_sender.send_now(MessageId::NavLap, Value::Uint32(progress.lap)).unwrap();
+
+        if !progress.geofences.is_empty() {
+            let outside = !progress.is_inside_geofence(&pos);
+            if outside && !progress.outside_geofence {
+                let names = progress.course.geofences.iter().map(|fence| fence.name.as_str()).collect::<Vec<&str>>();
+                warn!("Boat left the geofence ({}) at {:?}", names.join(", "), pos);
+            }
+            else if !outside && progress.outside_geofence {
+                info!("Boat is back inside the geofence");
+            }
+            progress.outside_geofence = outside;
+            metric_sender.send_now(MessageId::NavGeofenceAlert, Value::Uint32(outside as u32)).unwrap();
+        }
+    }
+
+    pub async fn run(metric_sender: MetricSender) {
+        let course_file = SETTINGS.get::<String>("navigation.course_file").unwrap();
+        let course = match Course::load(&course_file) {
+            Ok(course) => course,
+            Err(err) => {
+                error!("Could not load course {}: {}. Exiting thread!", course_file, err);
+                return;
+            }
+        };
+        info!("Loaded course with {} marks and {} geofences", course.marks.len(), course.geofences.len());
+
+        let mut progress = CourseProgress::new(course);
+        let mut metric_receiver = metric_sender.subscribe_as("navigation");
+        loop {
+            let Some(metric) = metric_receiver.recv().await else { break };
+            if metric.id() == MessageId::GpsPos {
+                match metric.value {
+                    Some(Value::Floats(floats)) => match GeoPoint::from_floats(&floats.values) {
+                        Some(pos) => Self::send_progress(&mut progress, pos, &metric_sender),
+                        None => warn!("GPS position too short")
+                    },
+                    _ => warn!("GPS unexpected Data format")
+                }
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("navigation.enabled").unwrap() {
+            info!("Navigation enabled!");
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use num_traits::FromPrimitive;
+use socketcan::{CanFrame, EmbeddedFrame, StandardId};
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::{can::{CanReceiver, CanSender, get_can_id, ids::CanIds}, helper::{bus::BusExt, curve::Curve, MetricSender, MetricSenderExt}, SETTINGS};
+
+// Drives the PMU cooling fans from the APMU, MPMU and PCS temperatures.
+// Every temperature maps to a duty through its curve and the highest duty is sent to all fans.
+// A fan whose recent RPM readings stay low while it is driven is considered stalled.
+// Temperatures are read as i16 in 0.01 °C like PCS_TEMP in the PMU component, fan speeds as u16 RPM, both big endian.
+// The duty frame (pmu.fan_duty_id, one duty byte per fan) is not part of a PMU protocol in this repo, match it
+// to the PMU firmware before enabling pmu.fan_control.
+pub struct FanControl {
+    can_sender: CanSender,
+    can_receiver: CanReceiver,
+    metric_sender: MetricSender,
+    duty_id: StandardId
+}
+
+const FAN_COUNT: usize = 4;
+
+struct TemperatureInput {
+    id: CanIds,
+    curve: Curve,
+    temp: Option<(f32, Instant)>
+}
+
+struct FanState {
+    // Last reading and when it arrived
+    rpm: Option<(u16, Instant)>,
+    // Since when the fan is driven without turning
+    stalled_since: Option<Instant>
+}
+
+impl FanControl {
+    pub fn new(can_sender: CanSender, can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
+        let duty_id = StandardId::new(SETTINGS.get::<u16>("pmu.fan_duty_id").unwrap()).expect("pmu.fan_duty_id is not a standard CAN id");
+        FanControl { can_sender, can_receiver, metric_sender, duty_id }
+    }
+
+    fn temperature_inputs() -> Vec<TemperatureInput> {
+        [
+            (CanIds::CanIdApmuTemp, "pmu.apmu_fan_curve"),
+            (CanIds::CanIdMpmuTemp, "pmu.mpmu_fan_curve"),
+            (CanIds::CanIdPCSTemp, "pmu.pcs_fan_curve"),
+        ]
+        .into_iter()
+        .map(|(id, key)| TemperatureInput { id, curve: SETTINGS.get::<Curve>(key).unwrap(), temp: None })
+        .collect()
+    }
+
+    fn fan_index(id: &CanIds) -> Option<usize> {
+        match id {
+            CanIds::CanIdFan1Rpm => Some(0),
+            CanIds::CanIdFan2Rpm => Some(1),
+            CanIds::CanIdFan3Rpm => Some(2),
+            CanIds::CanIdFan4Rpm => Some(3),
+            _ => None
+        }
+    }
+
+    // One duty byte (0..100 %) per fan
+    fn send_duty(&self, duty: u8) {
+        let frame = CanFrame::new(self.duty_id, &[duty; FAN_COUNT]).unwrap();
+        if self.can_sender.send(frame).is_err() {
+            warn!("Could not send fan duty");
+        }
+    }
+
+    fn read_frame(frame: &CanFrame, temperatures: &mut [TemperatureInput], fans: &mut [FanState]) {
+        let data = frame.data();
+        let id = match CanIds::from_u32(get_can_id(frame.id())) {
+            Some(id) if data.len() >= 2 => id,
+            _ => return
+        };
+        let raw: [u8; 2] = data[0..2].try_into().unwrap();
+        if let Some(input) = temperatures.iter_mut().find(|input| input.id == id) {
+            input.temp = Some((i16::from_be_bytes(raw) as f32 * 0.01, Instant::now()));
+        } else if let Some(fan) = Self::fan_index(&id) {
+            fans[fan].rpm = Some((u16::from_be_bytes(raw), Instant::now()));
+        }
+    }
+
+    async fn run(self) {
+        let update_interval = Duration::from_millis(SETTINGS.get::<u64>("pmu.fan_update_interval").unwrap());
+        // Without a recent temperature the fans run at full speed
+        let temp_timeout = SETTINGS.get::<u64>("pmu.fan_temp_timeout").unwrap() as u128;
+        let stall_min_duty = SETTINGS.get::<u8>("pmu.fan_stall_min_duty").unwrap();
+        let stall_rpm = SETTINGS.get::<u16>("pmu.fan_stall_rpm").unwrap();
+        let stall_time = SETTINGS.get::<u64>("pmu.fan_stall_time").unwrap() as u128;
+        let rpm_timeout = SETTINGS.get::<u64>("pmu.fan_rpm_timeout").unwrap() as u128;
+
+        let mut temperatures = Self::temperature_inputs();
+        let mut fans: Vec<FanState> = (0..FAN_COUNT).map(|_| FanState { rpm: None, stalled_since: None }).collect();
+        let mut last_alarm: u32 = 0;
+
+        let mut receiver = self.can_receiver.subscribe_as("fan_control");
+        let mut interval = tokio::time::interval(update_interval);
+        loop {
+            // Frames are read as they arrive, the receiver would lag behind the bus traffic between updates
+            tokio::select! {
+                frame = receiver.recv() => match frame {
+                    Some(frame) => {
+                        Self::read_frame(&frame, &mut temperatures, &mut fans);
+                        continue;
+                    },
+                    None => break
+                },
+                _ = interval.tick() => {}
+            }
+
+            let duty = temperatures
+                .iter()
+                .map(|input| match input.temp {
+                    Some((temp, ts)) if ts.elapsed().as_millis() < temp_timeout => input.curve.eval(temp),
+                    _ => 100.0
+                })
+                .fold(0.0f32, f32::max)
+                .clamp(0.0, 100.0) as u8;
+            self.send_duty(duty);
+            self.metric_sender.send_now(MessageId::FanDuty, Value::Uint32(duty as u32)).unwrap();
+
+            // Bit n is set while fan n + 1 is stalled
+            let mut alarm: u32 = 0;
+            for (idx, fan) in fans.iter_mut().enumerate() {
+                let fresh_rpm = fan.rpm.filter(|(_rpm, ts)| ts.elapsed().as_millis() < rpm_timeout).map(|(rpm, _ts)| rpm);
+                if duty >= stall_min_duty && fresh_rpm.is_some_and(|rpm| rpm < stall_rpm) {
+                    let since = *fan.stalled_since.get_or_insert_with(Instant::now);
+                    if since.elapsed().as_millis() >= stall_time {
+                        alarm |= 1 << idx;
+                    }
+                } else {
+                    fan.stalled_since = None;
+                }
+            }
+            if alarm != last_alarm {
+                if alarm != 0 {
+                    warn!("PMU fan stalled (mask {:#06b})", alarm);
+                }
+                last_alarm = alarm;
+            }
+            self.metric_sender.send_now(MessageId::FanStallAlarm, Value::Uint32(alarm)).unwrap();
+        }
+    }
+
+    pub fn start(self) {
+        info!("PMU fan control enabled!");
+        tokio::spawn(self.run());
+    }
+}
@@ -1,25 +1,30 @@
+mod fan_control;
+
 use log::info;
 use num_traits::{FromPrimitive, ToPrimitive};
 use socketcan::EmbeddedFrame;
 use wannsea_types::MessageId;
 use wannsea_types::boat_core_message::Value;
 
-use crate::{can::{CanReceiver, get_can_id, ids::CanIds}, helper::{MetricSender, MetricSenderExt}, SETTINGS};
+use crate::{can::{CanReceiver, CanSender, get_can_id, ids::CanIds}, helper::{bus::BusExt, MetricSender, MetricSenderExt}, SETTINGS};
+
+use self::fan_control::FanControl;
 
 pub struct PMU {
+    can_sender: CanSender,
     can_receiver: CanReceiver,
     metric_sender: MetricSender
 }
 
 impl PMU {
-    pub fn new(can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
-        PMU { can_receiver, metric_sender }
+    pub fn new(can_sender: CanSender, can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
+        PMU { can_sender, can_receiver, metric_sender }
     }
 
     pub async fn listen_can(can_receiver: CanReceiver, metric_sender: MetricSender) {
-        let mut receiver = can_receiver.subscribe();
+        let mut receiver = can_receiver.subscribe_as("pmu");
         loop {
-            let frame = receiver.recv().await.unwrap();
+            let Some(frame) = receiver.recv().await else { break };
             let data = frame.data().to_vec();
             let id = get_can_id(frame.id());
             let result = match FromPrimitive::from_u32(id) {
@@ -48,6 +53,10 @@ impl PMU {
             info!("PMU enabled!");
 
             tokio::spawn(Self::listen_can(self.can_receiver.clone(), self.metric_sender.clone()));
+
+            if SETTINGS.get::<bool>("pmu.fan_control").unwrap() {
+                FanControl::new(self.can_sender.clone(), self.can_receiver.clone(), self.metric_sender.clone()).start();
+            }
         }
     }
 }
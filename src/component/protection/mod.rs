@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::{helper::{bus::BusExt, curve::Curve, MetricSender, MetricSenderExt}, SETTINGS};
+
+// Computes the electrical power the motors may draw from the temperatures and the BMS current limit.
+// Every temperature maps to a share of max_power through its curve, the lowest share wins.
+// A temperature or BMS limit that is no longer updated is not trusted and limits to stale_power_share,
+// same for a temperature that never arrived.
+// The result is published as POWER_LIMIT and enforced by the throttle.
+pub struct Protection {
+    metric_sender: MetricSender
+}
+
+// A temperature reading and the curve mapping it to a share (0..1) of max_power
+struct TemperatureLimit {
+    name: &'static str,
+    id: MessageId,
+    curve: Curve,
+    temp: Option<(f32, Instant)>
+}
+
+impl Protection {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        Protection { metric_sender }
+    }
+
+    fn temperature_limits() -> Vec<TemperatureLimit> {
+        [
+            ("mosfet_temp", MessageId::EscMosfetTemp, "protection.mosfet_temp_curve"),
+            ("motor_temp", MessageId::EscMotorTemp, "protection.motor_temp_curve"),
+            ("battery_temp", MessageId::BatTmax, "protection.battery_temp_curve"),
+            ("pcs_temp", MessageId::PcsTemp, "protection.pcs_temp_curve"),
+        ]
+        .into_iter()
+        .map(|(name, id, key)| TemperatureLimit { name, id, curve: SETTINGS.get::<Curve>(key).unwrap(), temp: None })
+        .collect()
+    }
+
+    async fn run(metric_sender: MetricSender) {
+        let max_power = SETTINGS.get::<f32>("protection.max_power").unwrap();
+        let update_interval = Duration::from_millis(SETTINGS.get::<u64>("protection.update_interval").unwrap());
+        let stale_timeout = Duration::from_millis(SETTINGS.get::<u64>("protection.stale_timeout").unwrap());
+        let stale_power = max_power * SETTINGS.get::<f32>("protection.stale_power_share").unwrap().clamp(0.0, 1.0);
+
+        let mut temperatures = Self::temperature_limits();
+        let mut max_discharge_current: Option<(f32, Instant)> = None;
+        let mut voltage: Option<f32> = None;
+        let mut last_reason = String::new();
+
+        let mut receiver = metric_sender.subscribe_as("protection");
+        loop {
+            while let Some(metric) = receiver.try_recv() {
+                let id = metric.id();
+                match (id, metric.value) {
+                    (MessageId::MaxBatteryDischargeCurrent, Some(Value::Uint32(current))) => max_discharge_current = Some((current as f32, Instant::now())),
+                    (MessageId::EscInVoltage, Some(Value::Float(v))) => voltage = Some(v),
+                    (_, Some(value)) => {
+                        if let Some(limit) = temperatures.iter_mut().find(|limit| limit.id == id) {
+                            limit.temp = match value {
+                                Value::Float(temp) => Some((temp, Instant::now())),
+                                Value::Uint32(temp) => Some((temp as f32, Instant::now())),
+                                _ => limit.temp
+                            };
+                        }
+                    },
+                    _ => {}
+                }
+            }
+
+            let mut power_limit = max_power;
+            let mut reason = "none".to_string();
+            let mut limit_to = |allowed: f32, name: String| {
+                if allowed < power_limit {
+                    power_limit = allowed;
+                    reason = name;
+                }
+            };
+            for limit in &temperatures {
+                match limit.temp {
+                    Some((temp, ts)) if ts.elapsed() < stale_timeout => limit_to(max_power * limit.curve.eval(temp).clamp(0.0, 1.0), limit.name.to_string()),
+                    Some(_stale) => limit_to(stale_power, format!("{}_stale", limit.name)),
+                    None => limit_to(stale_power, format!("{}_missing", limit.name))
+                }
+            }
+            match (max_discharge_current, voltage) {
+                (Some((_current, ts)), _) if ts.elapsed() >= stale_timeout => limit_to(stale_power, "battery_current_stale".to_string()),
+                (Some((current, _ts)), Some(voltage)) => limit_to(current * voltage, "battery_current".to_string()),
+                _ => {}
+            }
+
+            if reason != last_reason {
+                if reason != "none" {
+                    warn!("Motor power limited to {:.0} W by {}", power_limit, reason);
+                }
+                metric_sender.send_now(MessageId::PowerLimitReason, Value::String(reason.clone())).unwrap();
+                last_reason = reason;
+            }
+            metric_sender.send_now(MessageId::PowerLimit, Value::Float(power_limit)).unwrap();
+
+            tokio::time::sleep(update_interval).await;
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("protection.enabled").unwrap() {
+            info!("Protection enabled!");
+
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
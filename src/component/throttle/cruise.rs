@@ -0,0 +1,206 @@
+use std::time::Duration;
+
+use log::{info, warn};
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::{helper::{bus::BusExt, MetricSender, MetricSenderExt}, SETTINGS};
+
+// Holds a target speed or input power by publishing CRUISE_SETPOINT, which the throttle uses
+// instead of the pilot input while it is fresh. Ramping, dead-man switch and the power limit of the
+// throttle still apply. Disengages when the pilot moves the throttle, the dead-man switch trips or
+// the measurement goes stale.
+pub struct CruiseControl {
+    metric_sender: MetricSender
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum CruiseMode {
+    Off = 0,
+    Speed = 1,
+    Power = 2
+}
+
+struct Pid {
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    integral: f32,
+    last_error: Option<f32>
+}
+
+impl Pid {
+    // Output bounded to 0..max, the integral only grows while the output is not saturated (anti-windup)
+    fn update(&mut self, error: f32, dt: f32, max: f32) -> f32 {
+        let derivative = self.last_error.map_or(0.0, |last| (error - last) / dt);
+        self.last_error = Some(error);
+
+        let unbounded = self.kp * error + self.integral + self.ki * error * dt + self.kd * derivative;
+        let output = unbounded.clamp(0.0, max);
+        if output == unbounded || (unbounded > max && error < 0.0) || (unbounded < 0.0 && error > 0.0) {
+            self.integral += self.ki * error * dt;
+        }
+        output
+    }
+
+    // Start from the current throttle so engaging does not cause a jump
+    fn reset(&mut self, output: f32) {
+        self.integral = output;
+        self.last_error = None;
+    }
+}
+
+struct CruiseState {
+    mode: CruiseMode,
+    target: f32,
+    // Speed in knots or input power in W, with the time it was received
+    speed: Option<(f32, Instant)>,
+    power: Option<(f32, Instant)>,
+    pilot_input: f32,
+    // Pilot input when cruise control was engaged
+    engaged_input: f32,
+    throttle_setpoint: f32,
+    throttle_limit: f32,
+    power_limit: Option<f32>,
+    deadman: bool
+}
+
+impl CruiseControl {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        CruiseControl { metric_sender }
+    }
+
+    fn engage(state: &mut CruiseState, pid: &mut Pid, mode: CruiseMode, target: f32) {
+        if target <= 0.0 {
+            Self::disengage(state, "command");
+            return;
+        }
+        if state.deadman {
+            warn!("Cruise control can not be engaged while the throttle is not armed");
+            return;
+        }
+        if state.mode != mode {
+            pid.reset(state.throttle_setpoint.max(0.0));
+        }
+        info!("Cruise control holding {:?} at {}", mode, target);
+        state.mode = mode;
+        state.target = target;
+        state.engaged_input = state.pilot_input;
+    }
+
+    fn disengage(state: &mut CruiseState, reason: &str) {
+        if state.mode != CruiseMode::Off {
+            info!("Cruise control disengaged ({})", reason);
+            state.mode = CruiseMode::Off;
+        }
+    }
+
+    fn handle_metric(state: &mut CruiseState, pid: &mut Pid, id: MessageId, value: Value, speed_source: MessageId) {
+        let now = Instant::now();
+        match (id, value) {
+            (MessageId::CruiseSpeedCommand, Value::Float(target)) => Self::engage(state, pid, CruiseMode::Speed, target),
+            (MessageId::CruisePowerCommand, Value::Float(target)) => Self::engage(state, pid, CruiseMode::Power, target),
+            (MessageId::GpsSpeed, Value::Double(speed)) if speed_source == MessageId::GpsSpeed => state.speed = Some((speed as f32, now)),
+            (MessageId::FusedVelocity, Value::Floats(velocity)) if speed_source == MessageId::FusedVelocity && velocity.values.len() >= 2 => {
+                // East and north in m/s
+                let speed = (velocity.values[0].powi(2) + velocity.values[1].powi(2)).sqrt() * 3600.0 / 1852.0;
+                state.speed = Some((speed, now));
+            },
+            (MessageId::EscTotalInPower, Value::Float(power)) => state.power = Some((power, now)),
+            (MessageId::ThrottleCommand | MessageId::ThrottleAnalog, Value::Float(input)) => state.pilot_input = input,
+            (MessageId::ThrottleSetpoint, Value::Float(setpoint)) => state.throttle_setpoint = setpoint,
+            (MessageId::ThrottleLimit, Value::Float(limit)) => state.throttle_limit = limit,
+            (MessageId::PowerLimit, Value::Float(limit)) => state.power_limit = Some(limit),
+            (MessageId::ThrottleDeadman, Value::Uint32(deadman)) => state.deadman = deadman != 0,
+            _ => {}
+        }
+    }
+
+    async fn run(metric_sender: MetricSender) {
+        let update_interval = Duration::from_millis(SETTINGS.get::<u64>("cruise_control.update_interval").unwrap());
+        let dt = update_interval.as_secs_f32();
+        let stale_timeout = SETTINGS.get::<u64>("cruise_control.stale_timeout").unwrap() as u128;
+        let override_threshold = SETTINGS.get::<f32>("cruise_control.override_threshold").unwrap();
+        let speed_source = MessageId::from_str_name(&SETTINGS.get::<String>("cruise_control.speed_source").unwrap()).unwrap();
+
+        // Error in knots and W are far apart, each mode has its own gains
+        let mut speed_pid = Pid {
+            kp: SETTINGS.get::<f32>("cruise_control.speed_kp").unwrap(),
+            ki: SETTINGS.get::<f32>("cruise_control.speed_ki").unwrap(),
+            kd: SETTINGS.get::<f32>("cruise_control.speed_kd").unwrap(),
+            integral: 0.0,
+            last_error: None
+        };
+        let mut power_pid = Pid {
+            kp: SETTINGS.get::<f32>("cruise_control.power_kp").unwrap(),
+            ki: SETTINGS.get::<f32>("cruise_control.power_ki").unwrap(),
+            kd: SETTINGS.get::<f32>("cruise_control.power_kd").unwrap(),
+            integral: 0.0,
+            last_error: None
+        };
+
+        let mut state = CruiseState {
+            mode: CruiseMode::Off,
+            target: 0.0,
+            speed: None,
+            power: None,
+            pilot_input: 0.0,
+            engaged_input: 0.0,
+            throttle_setpoint: 0.0,
+            throttle_limit: 1.0,
+            power_limit: None,
+            deadman: true
+        };
+
+        let mut receiver = metric_sender.subscribe_as("cruise_control");
+        loop {
+            while let Some(metric) = receiver.try_recv() {
+                let id = metric.id();
+                let pid = match id {
+                    MessageId::CruisePowerCommand => &mut power_pid,
+                    _ => &mut speed_pid
+                };
+                if let Some(value) = metric.value {
+                    Self::handle_metric(&mut state, pid, id, value, speed_source);
+                }
+            }
+
+            if state.mode != CruiseMode::Off {
+                let measurement = if state.mode == CruiseMode::Speed { state.speed } else { state.power };
+                if (state.pilot_input - state.engaged_input).abs() > override_threshold {
+                    Self::disengage(&mut state, "throttle override");
+                } else if state.deadman {
+                    Self::disengage(&mut state, "dead-man switch");
+                } else if !measurement.is_some_and(|(_value, ts)| ts.elapsed().as_millis() < stale_timeout) {
+                    Self::disengage(&mut state, "stale measurement");
+                }
+            }
+
+            match (state.mode, state.speed, state.power) {
+                (CruiseMode::Speed, Some((speed, _ts)), _) => {
+                    let output = speed_pid.update(state.target - speed, dt, state.throttle_limit);
+                    metric_sender.send_now(MessageId::CruiseSetpoint, Value::Float(output)).unwrap();
+                },
+                (CruiseMode::Power, _, Some((power, _ts))) => {
+                    // Never aim above what the protection allows
+                    let target = state.power_limit.map_or(state.target, |limit| state.target.min(limit));
+                    let output = power_pid.update(target - power, dt, state.throttle_limit);
+                    metric_sender.send_now(MessageId::CruiseSetpoint, Value::Float(output)).unwrap();
+                },
+                _ => {}
+            }
+            metric_sender.send_now(MessageId::CruiseState, Value::Uint32(state.mode as u32)).unwrap();
+
+            tokio::time::sleep(update_interval).await;
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("cruise_control.enabled").unwrap() {
+            info!("Cruise Control enabled!");
+
+            tokio::spawn(Self::run(self.metric_sender.clone()));
+        }
+    }
+}
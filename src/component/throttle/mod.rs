@@ -0,0 +1,250 @@
+pub mod cruise;
+
+use std::time::Duration;
+
+use log::{info, warn};
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId};
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::MessageId;
+
+use crate::{can::{CanReceiver, CanSender, get_can_id}, component::vesc::{VescController, VescMessageIds}, helper::{bus::BusExt, MetricSender, MetricSenderExt}, SETTINGS};
+
+// The boat core owns the throttle: the input of the selected source is ramped, held within the
+// POWER_LIMIT of the protection component and sent to every VESC. Without input for the dead-man
+// timeout the throttle goes to zero and stays there until the input is back in neutral.
+// While cruise control is engaged its CRUISE_SETPOINT replaces the pilot input.
+pub struct Throttle {
+    can_sender: CanSender,
+    can_receiver: CanReceiver,
+    metric_sender: MetricSender
+}
+
+#[derive(PartialEq)]
+enum ThrottleSource {
+    // THROTTLE_COMMAND on the metric bus, from local components or clients with the throttle.remote_token
+    Command,
+    // Lever on CAN, published as THROTTLE_ANALOG
+    Analog
+}
+
+#[derive(PartialEq)]
+enum OutputMode {
+    Current,
+    Duty
+}
+
+struct ThrottleConfig {
+    source: ThrottleSource,
+    mode: OutputMode,
+    max_current: f32,
+    max_duty: f32,
+    ramp_up: f32,
+    ramp_down: f32,
+    deadband: f32,
+    deadman_timeout: u128,
+    // Protection is enabled, without a recent POWER_LIMIT the throttle stays at zero
+    enforce_power_limit: bool,
+    power_limit_timeout: u128,
+    power_limit_recovery: f32
+}
+
+struct ThrottleState {
+    input: f32,
+    last_input: Option<Instant>,
+    // Input has been in neutral since start or the last dead-man trip
+    armed: bool,
+    // Ramped and limited output, -1..1
+    setpoint: f32,
+    power_limit: Option<(f32, Instant)>,
+    voltage: Option<f32>,
+    // Highest output (0..1) while the input power is above the limit, recovers over time
+    power_scale: f32,
+    cruise_setpoint: Option<(f32, Instant)>
+}
+
+impl Throttle {
+    pub fn new(can_sender: CanSender, can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
+        Throttle { can_sender, can_receiver, metric_sender }
+    }
+
+    fn load_config() -> ThrottleConfig {
+        ThrottleConfig {
+            source: match SETTINGS.get::<String>("throttle.source").unwrap().as_str() {
+                "analog" => ThrottleSource::Analog,
+                _ => ThrottleSource::Command
+            },
+            mode: match SETTINGS.get::<String>("throttle.mode").unwrap().as_str() {
+                "duty" => OutputMode::Duty,
+                _ => OutputMode::Current
+            },
+            max_current: SETTINGS.get::<f32>("throttle.max_current").unwrap(),
+            max_duty: SETTINGS.get::<f32>("throttle.max_duty").unwrap(),
+            ramp_up: SETTINGS.get::<f32>("throttle.ramp_up").unwrap(),
+            ramp_down: SETTINGS.get::<f32>("throttle.ramp_down").unwrap(),
+            deadband: SETTINGS.get::<f32>("throttle.deadband").unwrap(),
+            deadman_timeout: SETTINGS.get::<u64>("throttle.deadman_timeout").unwrap() as u128,
+            enforce_power_limit: SETTINGS.get::<bool>("protection.enabled").unwrap(),
+            power_limit_timeout: SETTINGS.get::<u64>("throttle.power_limit_timeout").unwrap() as u128,
+            power_limit_recovery: SETTINGS.get::<f32>("throttle.power_limit_recovery").unwrap()
+        }
+    }
+
+    // Lever position as raw u16 (big endian, first two bytes) on throttle.analog_can_id, mapped to -1..1
+    // around the center position
+    async fn listen_analog(can_receiver: CanReceiver, metric_sender: MetricSender) {
+        let can_id = SETTINGS.get::<u32>("throttle.analog_can_id").unwrap();
+        let min = SETTINGS.get::<f32>("throttle.analog_min").unwrap();
+        let center = SETTINGS.get::<f32>("throttle.analog_center").unwrap();
+        let max = SETTINGS.get::<f32>("throttle.analog_max").unwrap();
+
+        let mut receiver = can_receiver.subscribe_as("throttle_analog");
+        loop {
+            let Some(frame) = receiver.recv().await else { break };
+            if get_can_id(frame.id()) != can_id || frame.dlc() < 2 {
+                continue;
+            }
+            let raw = u16::from_be_bytes(frame.data()[0..2].try_into().unwrap()) as f32;
+            let position = if raw >= center { (raw - center) / (max - center) } else { (raw - center) / (center - min) };
+            metric_sender.send_now(MessageId::ThrottleAnalog, Value::Float(position.clamp(-1.0, 1.0))).unwrap();
+        }
+    }
+
+    fn handle_metric(config: &ThrottleConfig, state: &mut ThrottleState, id: MessageId, value: Value) {
+        match (id, value) {
+            (MessageId::ThrottleCommand, Value::Float(input)) if config.source == ThrottleSource::Command => {
+                state.input = input.clamp(-1.0, 1.0);
+                state.last_input = Some(Instant::now());
+            },
+            (MessageId::ThrottleAnalog, Value::Float(input)) if config.source == ThrottleSource::Analog => {
+                state.input = input;
+                state.last_input = Some(Instant::now());
+            },
+            (MessageId::CruiseSetpoint, Value::Float(setpoint)) => state.cruise_setpoint = Some((setpoint, Instant::now())),
+            (MessageId::PowerLimit, Value::Float(limit)) => state.power_limit = Some((limit, Instant::now())),
+            (MessageId::EscInVoltage, Value::Float(voltage)) => state.voltage = Some(voltage),
+            (MessageId::EscTotalInCurrent, Value::Float(current)) => {
+                if let (Some((limit, _ts)), Some(voltage)) = (state.power_limit, state.voltage) {
+                    let power = current.abs() * voltage;
+                    // Near zero output the power is still from before (ramping down, other controllers, a late
+                    // frame), scaling the output down from there would lock the throttle until it recovers
+                    if power > limit && state.setpoint.abs() >= config.deadband {
+                        // Output that draws the limit if the power follows the output. Based on the current
+                        // output, so repeated status frames do not compound the reduction.
+                        state.power_scale = state.power_scale.min(state.setpoint.abs() * (limit / power).clamp(0.0, 1.0));
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // Output at which all controllers together draw the power limit from the pack. The motor current is at
+    // least the battery current, so this cap errs on the safe side. Only for current output.
+    fn current_cap(config: &ThrottleConfig, state: &ThrottleState, controllers: usize) -> f32 {
+        match (state.power_limit, state.voltage) {
+            (Some((limit, _ts)), Some(voltage)) if config.mode == OutputMode::Current && voltage > 0.0 && controllers > 0 => {
+                (limit / voltage / controllers as f32 / config.max_current).clamp(0.0, 1.0)
+            },
+            _ => 1.0
+        }
+    }
+
+    fn send_setpoint(can_sender: &CanSender, config: &ThrottleConfig, controllers: &[VescController], setpoint: f32) {
+        let (msg_id, value) = match config.mode {
+            OutputMode::Current => (VescMessageIds::SetCurrent as u32, (setpoint * config.max_current * 1000.0) as i32),
+            OutputMode::Duty => (VescMessageIds::SetDuty as u32, (setpoint * config.max_duty * 100_000.0) as i32)
+        };
+        for controller in controllers {
+            let id = ExtendedId::new((msg_id << 8) | controller.id).unwrap();
+            let frame = CanFrame::new(id, &value.to_be_bytes()).unwrap();
+            if can_sender.send(frame).is_err() {
+                warn!("Could not send throttle to VESC {}", controller.name);
+            }
+        }
+    }
+
+    async fn run(can_sender: CanSender, metric_sender: MetricSender) {
+        let config = Self::load_config();
+        let controllers = SETTINGS.get::<Vec<VescController>>("vesc.controllers").unwrap();
+        let update_interval = Duration::from_millis(SETTINGS.get::<u64>("throttle.update_interval").unwrap());
+        let dt = update_interval.as_secs_f32();
+        // Cruise control publishes every one of its updates, three missed ones mean it disengaged
+        let cruise_timeout = Duration::from_millis(SETTINGS.get::<u64>("cruise_control.update_interval").unwrap()) * 3;
+
+        let mut state = ThrottleState {
+            input: 0.0,
+            last_input: None,
+            armed: false,
+            setpoint: 0.0,
+            power_limit: None,
+            voltage: None,
+            power_scale: 1.0,
+            cruise_setpoint: None
+        };
+        let mut receiver = metric_sender.subscribe_as("throttle");
+
+        loop {
+            while let Some(metric) = receiver.try_recv() {
+                let id = metric.id();
+                if let Some(value) = metric.value {
+                    Self::handle_metric(&config, &mut state, id, value);
+                }
+            }
+
+            let input = if state.input.abs() < config.deadband { 0.0 } else { state.input };
+            let alive = state.last_input.is_some_and(|ts| ts.elapsed().as_millis() < config.deadman_timeout);
+            if !alive && state.armed {
+                warn!("No throttle input for {} ms, dead-man switch released", config.deadman_timeout);
+                state.armed = false;
+            }
+            if alive && !state.armed && input == 0.0 {
+                info!("Throttle armed");
+                state.armed = true;
+            }
+
+            state.power_scale = (state.power_scale + config.power_limit_recovery * dt).min(1.0);
+            let limit = match state.power_limit {
+                _ if !config.enforce_power_limit => state.power_scale,
+                Some((power_limit, ts)) if power_limit > 0.0 && ts.elapsed().as_millis() < config.power_limit_timeout => {
+                    state.power_scale.min(Self::current_cap(&config, &state, controllers.len()))
+                },
+                _ => 0.0
+            };
+
+            let input = match state.cruise_setpoint {
+                Some((setpoint, ts)) if ts.elapsed() < cruise_timeout => setpoint,
+                _ => input
+            };
+            let mut target = if state.armed { input.clamp(-limit, limit) } else { 0.0 };
+            if target * state.setpoint < 0.0 {
+                // Changing direction, slow down to zero first
+                target = 0.0;
+            }
+            let accelerating = target.abs() > state.setpoint.abs();
+            let max_step = if accelerating { config.ramp_up * dt } else { config.ramp_down * dt };
+            state.setpoint += (target - state.setpoint).clamp(-max_step, max_step);
+
+            Self::send_setpoint(&can_sender, &config, &controllers, state.setpoint);
+            metric_sender.send_now(MessageId::ThrottleSetpoint, Value::Float(state.setpoint)).unwrap();
+            metric_sender.send_now(MessageId::ThrottleLimit, Value::Float(limit)).unwrap();
+            metric_sender.send_now(MessageId::ThrottleDeadman, Value::Uint32(!state.armed as u32)).unwrap();
+
+            tokio::time::sleep(update_interval).await;
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("throttle.enabled").unwrap() {
+            info!("Throttle enabled!");
+
+            if SETTINGS.get::<String>("throttle.source").unwrap() == "analog" {
+                tokio::spawn(Self::listen_analog(self.can_receiver.clone(), self.metric_sender.clone()));
+            }
+            else if SETTINGS.get::<String>("throttle.remote_token").unwrap().is_empty() {
+                warn!("No throttle.remote_token set, THROTTLE_COMMAND is only accepted from local components");
+            }
+            tokio::spawn(Self::run(self.can_sender.clone(), self.metric_sender.clone()));
+        }
+    }
+}
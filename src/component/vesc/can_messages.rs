@@ -1,13 +1,10 @@
 
 use std::fmt::Debug;
 
-use log::debug;
 use num_derive::FromPrimitive;
 use num_traits::ToPrimitive;
 use wannsea_types::{boat_core_message::Value, MessageId};
 
-use crate::helper::{MetricSender, MetricSenderExt};
-
 #[derive(FromPrimitive)]
 pub enum VescMessageIds {
     SetDuty = 0,
@@ -40,13 +37,46 @@ pub enum VescMessageIds {
     Status5 = 27,
 }
 
+// How the values of all controllers are combined into the global Esc* metric
+pub enum Aggregate {
+    Sum,
+    Max,
+    Mean
+}
+
+// Per controller metric (StringFloatMap keyed by controller name) and aggregation of a global Esc* metric
+pub fn controller_metric(global: MessageId) -> Option<(MessageId, Aggregate)> {
+    match global {
+        MessageId::ThrottlePos => Some((MessageId::VescThrottlePos, Aggregate::Mean)),
+        MessageId::EscRpm => Some((MessageId::VescRpm, Aggregate::Mean)),
+        MessageId::EscTotalCurrent => Some((MessageId::VescTotalCurrent, Aggregate::Sum)),
+        MessageId::EscDutyCycle => Some((MessageId::VescDutyCycle, Aggregate::Mean)),
+        MessageId::EscAmpHours => Some((MessageId::VescAmpHours, Aggregate::Sum)),
+        MessageId::EscAmpHoursCharged => Some((MessageId::VescAmpHoursCharged, Aggregate::Sum)),
+        MessageId::EscWattHours => Some((MessageId::VescWattHours, Aggregate::Sum)),
+        MessageId::EscWattHoursCharged => Some((MessageId::VescWattHoursCharged, Aggregate::Sum)),
+        MessageId::EscMosfetTemp => Some((MessageId::VescMosfetTemp, Aggregate::Max)),
+        MessageId::EscMotorTemp => Some((MessageId::VescMotorTemp, Aggregate::Max)),
+        MessageId::EscTotalInCurrent => Some((MessageId::VescTotalInCurrent, Aggregate::Sum)),
+        MessageId::EscPidPos => Some((MessageId::VescPidPos, Aggregate::Mean)),
+        MessageId::EscTachometer => Some((MessageId::VescTachometer, Aggregate::Sum)),
+        MessageId::EscInVoltage => Some((MessageId::VescInVoltage, Aggregate::Mean)),
+        _ => None
+    }
+}
+
+// Global metrics keep the value type they had with a single controller
+pub fn global_value(global: MessageId, value: f64) -> Value {
+    match global {
+        MessageId::EscRpm | MessageId::EscTachometer => Value::Int32(value.round() as i32),
+        _ => Value::Float(value as f32)
+    }
+}
+
 pub trait CanMessage: Sized + Debug {
     fn from_can_data(data: &[u8]) -> Self;
-    async fn send_metrics(&self, metric_sender: &MetricSender);
-    async fn parse_and_send(data: &[u8], metric_sender: &MetricSender) {
-        let metrics = Self::from_can_data(data);
-        let _ = metrics.send_metrics(metric_sender).await;
-    }
+    // Decoded values by their global Esc* metric
+    fn values(&self) -> Vec<(MessageId, f64)>;
 }
 
 #[derive(Debug)]
@@ -61,8 +91,8 @@ impl CanMessage for SetDutyMsg {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::ThrottlePos, Value::Float(self.duty_cycle.to_f32().unwrap() / 100000.0f32)).unwrap();
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![(MessageId::ThrottlePos, self.duty_cycle.to_f64().unwrap() / 100000.0)]
     }
 }
 
@@ -82,10 +112,12 @@ impl CanMessage for StatusMsg1 {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::EscRpm, Value::Int32(self.rpm)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscTotalCurrent, Value::Float(self.total_current)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscDutyCycle, Value::Float(self.duty_cycle)).unwrap();
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![
+            (MessageId::EscRpm, self.rpm as f64),
+            (MessageId::EscTotalCurrent, self.total_current as f64),
+            (MessageId::EscDutyCycle, self.duty_cycle as f64)
+        ]
     }
 }
 
@@ -103,9 +135,11 @@ impl CanMessage for StatusMsg2 {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::EscAmpHours, Value::Float(self.amp_hours)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscAmpHoursCharged, Value::Float(self.amp_hours_charged)).unwrap();
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![
+            (MessageId::EscAmpHours, self.amp_hours as f64),
+            (MessageId::EscAmpHoursCharged, self.amp_hours_charged as f64)
+        ]
     }
 }
 
@@ -123,9 +157,11 @@ impl CanMessage for StatusMsg3 {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::EscWattHours, Value::Float(self.watt_hours)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscWattHoursCharged, Value::Float(self.watt_hours_charged)).unwrap();
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![
+            (MessageId::EscWattHours, self.watt_hours as f64),
+            (MessageId::EscWattHoursCharged, self.watt_hours_charged as f64)
+        ]
     }
 }
 
@@ -147,11 +183,13 @@ impl CanMessage for StatusMsg4 {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::EscMosfetTemp, Value::Float(self.mosfet_temp)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscMotorTemp, Value::Float(self.motor_temp)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscTotalInCurrent, Value::Float(self.total_in_cur)).unwrap();
-        let _ = metric_sender.send_now(MessageId::EscPidPos, Value::Float(self.pid_pos)).unwrap();
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![
+            (MessageId::EscMosfetTemp, self.mosfet_temp as f64),
+            (MessageId::EscMotorTemp, self.motor_temp as f64),
+            (MessageId::EscTotalInCurrent, self.total_in_cur as f64),
+            (MessageId::EscPidPos, self.pid_pos as f64)
+        ]
     }
 }
 
@@ -169,8 +207,10 @@ impl CanMessage for StatusMsg5 {
         }
     }
 
-    async fn send_metrics(&self, metric_sender: &MetricSender) {
-        let _ = metric_sender.send_now(MessageId::EscTachometer, Value::Int32(self.tachometer));
-        let _ = metric_sender.send_now(MessageId::EscInVoltage, Value::Float(self.in_voltage));
+    fn values(&self) -> Vec<(MessageId, f64)> {
+        vec![
+            (MessageId::EscTachometer, self.tachometer as f64),
+            (MessageId::EscInVoltage, self.in_voltage as f64)
+        ]
     }
 }
\ No newline at end of file
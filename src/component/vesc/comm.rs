@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use num_traits::FromPrimitive;
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId};
+use tokio::time::{sleep, timeout_at, Instant};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{MessageId, StringFloatMap};
+
+use crate::SETTINGS;
+use crate::{can::{CanReceiver, CanSender, get_can_id}, helper::{bus::{BusExt, BusReceiver}, MetricSender, MetricSenderExt}};
+
+use super::can_messages::VescMessageIds;
+use super::VescController;
+
+// VESC COMM packets (the protocol VESC Tool speaks over USB) tunneled through CAN.
+// Requests of up to 6 bytes fit into a single ProcessShortBuffer frame. The controller answers to our
+// CAN id, either with a ProcessShortBuffer or by filling our rx buffer and sending ProcessRxBuffer
+// with length and CRC. Controllers are polled one after the other so their replies do not interleave.
+// https://github.com/vedderb/bldc/blob/master/comm/comm_can.c
+
+#[derive(Clone, Copy, Debug)]
+enum CommPacketId {
+    FwVersion = 0,
+    GetValues = 4,
+}
+
+// Ask the controller to process the command and send the reply back
+const SEND_MODE_PROCESS_AND_REPLY: u8 = 0;
+
+// mc_fault_code of the VESC firmware
+const FAULT_NAMES: [&str; 30] = [
+    "NONE",
+    "OVER_VOLTAGE",
+    "UNDER_VOLTAGE",
+    "DRV",
+    "ABS_OVER_CURRENT",
+    "OVER_TEMP_FET",
+    "OVER_TEMP_MOTOR",
+    "GATE_DRIVER_OVER_VOLTAGE",
+    "GATE_DRIVER_UNDER_VOLTAGE",
+    "MCU_UNDER_VOLTAGE",
+    "BOOTING_FROM_WATCHDOG_RESET",
+    "ENCODER_SPI",
+    "ENCODER_SINCOS_BELOW_MIN_AMPLITUDE",
+    "ENCODER_SINCOS_ABOVE_MAX_AMPLITUDE",
+    "FLASH_CORRUPTION",
+    "HIGH_OFFSET_CURRENT_SENSOR_1",
+    "HIGH_OFFSET_CURRENT_SENSOR_2",
+    "HIGH_OFFSET_CURRENT_SENSOR_3",
+    "UNBALANCED_CURRENTS",
+    "BRK",
+    "RESOLVER_LOT",
+    "RESOLVER_DOS",
+    "RESOLVER_LOS",
+    "FLASH_CORRUPTION_APP_CFG",
+    "FLASH_CORRUPTION_MC_CFG",
+    "ENCODER_NO_MAGNET",
+    "ENCODER_MAGNET_TOO_STRONG",
+    "PHASE_FILTER",
+    "ENCODER_FAULT",
+    "LV_OUTPUT_FAULT",
+];
+
+fn fault_name(code: u8) -> &'static str {
+    FAULT_NAMES.get(code as usize).copied().unwrap_or("UNKNOWN")
+}
+
+// CRC-16/XMODEM as used by the VESC packet layer
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+pub struct VescComm {
+    can_sender: CanSender,
+    can_receiver: CanReceiver,
+    metric_sender: MetricSender,
+    controllers: Vec<VescController>,
+    // Our own CAN id, replies are addressed to it
+    host_id: u8,
+    reply_timeout: Duration,
+    // Last fault code per controller, alarms are only sent on change
+    faults: HashMap<u32, u8>,
+}
+
+impl VescComm {
+    pub async fn start(can_sender: CanSender, can_receiver: CanReceiver, metric_sender: MetricSender, controllers: Vec<VescController>) {
+        let host_id = SETTINGS.get::<u8>("vesc.host_id").unwrap();
+        let reply_timeout = Duration::from_millis(SETTINGS.get::<u64>("vesc.reply_timeout").unwrap());
+        let mut comm = VescComm { can_sender, can_receiver, metric_sender, controllers, host_id, reply_timeout, faults: HashMap::new() };
+        comm.run().await;
+    }
+
+    fn send_request(&self, vesc_id: u32, packet: CommPacketId) {
+        let id = ExtendedId::new(((VescMessageIds::ProcessShortBuffer as u32) << 8) | vesc_id).unwrap();
+        let frame = CanFrame::new(id, &[self.host_id, SEND_MODE_PROCESS_AND_REPLY, packet as u8]).unwrap();
+        if self.can_sender.send(frame).is_err() {
+            debug!("Error sending VESC {:?} request", packet);
+        }
+    }
+
+    // Collects the reply frames addressed to us until the controller tells us to process the buffer
+    async fn receive_reply(&self, receiver: &mut BusReceiver<CanFrame>, vesc_id: u32, packet: CommPacketId) -> Option<Vec<u8>> {
+        let deadline = Instant::now() + self.reply_timeout;
+        let mut buffer: Vec<u8> = Vec::new();
+
+        loop {
+            let frame = match timeout_at(deadline, receiver.recv()).await {
+                Ok(Some(frame)) => frame,
+                _ => return None
+            };
+
+            let can_id = get_can_id(frame.id());
+            let data = frame.data();
+            if !frame.is_extended() || can_id & 0xFF != self.host_id as u32 || data.is_empty() {
+                continue;
+            }
+
+            let reply = match VescMessageIds::from_u32(can_id >> 8) {
+                Some(VescMessageIds::FillRxBuffer) => {
+                    let offset = data[0] as usize;
+                    Self::fill(&mut buffer, offset, &data[1..]);
+                    continue;
+                },
+                Some(VescMessageIds::FillRxBufferLong) if data.len() > 2 => {
+                    let offset = u16::from_be_bytes(data[0..2].try_into().unwrap()) as usize;
+                    Self::fill(&mut buffer, offset, &data[2..]);
+                    continue;
+                },
+                Some(VescMessageIds::ProcessRxBuffer) if data.len() >= 6 => {
+                    let len = u16::from_be_bytes(data[2..4].try_into().unwrap()) as usize;
+                    let crc = u16::from_be_bytes(data[4..6].try_into().unwrap());
+                    if data[0] as u32 != vesc_id || buffer.len() < len || crc16(&buffer[..len]) != crc {
+                        warn!("Dropping invalid VESC {:?} reply from {}", packet, data[0]);
+                        buffer.clear();
+                        continue;
+                    }
+                    buffer[..len].to_vec()
+                },
+                Some(VescMessageIds::ProcessShortBuffer) if data.len() > 2 && data[0] as u32 == vesc_id => data[2..].to_vec(),
+                _ => continue
+            };
+
+            if reply.first() == Some(&(packet as u8)) {
+                return Some(reply);
+            }
+        }
+    }
+
+    fn fill(buffer: &mut Vec<u8>, offset: usize, data: &[u8]) {
+        if buffer.len() < offset + data.len() {
+            buffer.resize(offset + data.len(), 0);
+        }
+        buffer[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    async fn request(&self, vesc_id: u32, packet: CommPacketId) -> Option<Vec<u8>> {
+        // Subscribe before sending so the reply can not be missed
+        let mut receiver = self.can_receiver.subscribe_as("vesc_comm");
+        self.send_request(vesc_id, packet);
+        self.receive_reply(&mut receiver, vesc_id, packet).await
+    }
+
+    fn send_controller_metric(&self, id: MessageId, name: &str, value: f32) {
+        let items = HashMap::from([(name.to_string(), value)]);
+        self.metric_sender.send_now(id, Value::StringFloatMap(StringFloatMap { items })).unwrap();
+    }
+
+    fn handle_fw_version(&self, controller: &VescController, reply: &[u8]) {
+        if reply.len() < 3 {
+            return;
+        }
+        let hardware = reply[3..].split(|byte| *byte == 0).next().map(String::from_utf8_lossy).unwrap_or_default();
+        info!("VESC {} ({}) runs firmware {}.{:02} on {}", controller.name, controller.id, reply[1], reply[2], hardware);
+        self.send_controller_metric(MessageId::VescFirmwareVersion, &controller.name, reply[1] as f32 + reply[2] as f32 / 100.0);
+    }
+
+    // Only the fault code is taken from COMM_GET_VALUES, everything else is already sent in the status frames
+    fn handle_values(&mut self, controller: &VescController, reply: &[u8]) {
+        const FAULT_CODE_IDX: usize = 53;
+        if reply.len() <= FAULT_CODE_IDX {
+            warn!("VESC {} sent a short COMM_GET_VALUES reply ({} bytes)", controller.name, reply.len());
+            return;
+        }
+        let fault = reply[FAULT_CODE_IDX];
+        self.send_controller_metric(MessageId::VescFaultCode, &controller.name, fault as f32);
+
+        if self.faults.insert(controller.id, fault) != Some(fault) {
+            let alarm = format!("{}: {}", controller.name, fault_name(fault));
+            if fault != 0 {
+                warn!("VESC fault {}", alarm);
+            }
+            self.metric_sender.send_now(MessageId::VescFaultAlarm, Value::String(alarm)).unwrap();
+        }
+    }
+
+    async fn run(&mut self) {
+        let poll_interval = Duration::from_millis(SETTINGS.get::<u64>("vesc.poll_interval").unwrap());
+        let controllers = self.controllers.clone();
+
+        for controller in &controllers {
+            match self.request(controller.id, CommPacketId::FwVersion).await {
+                Some(reply) => self.handle_fw_version(controller, &reply),
+                None => warn!("VESC {} ({}) did not answer the firmware version request", controller.name, controller.id)
+            }
+        }
+
+        loop {
+            for controller in &controllers {
+                match self.request(controller.id, CommPacketId::GetValues).await {
+                    Some(reply) => self.handle_values(controller, &reply),
+                    None => debug!("No COMM_GET_VALUES reply from VESC {}", controller.name)
+                }
+            }
+            sleep(poll_interval).await;
+        }
+    }
+}
@@ -1,12 +1,22 @@
 mod can_messages;
+mod comm;
 mod read_thread;
 
 use log::info;
+use serde::Deserialize;
 
 use crate::{can::{CanSender, CanReceiver}, helper::MetricSender, SETTINGS};
 
-use self::read_thread::VescReadThread;
+use self::{comm::VescComm, read_thread::VescReadThread};
+pub use self::can_messages::VescMessageIds;
 
+#[derive(Deserialize, Clone)]
+pub struct VescController {
+    // CAN id set in VESC Tool
+    pub id: u32,
+    // Key of the controller in the per controller metrics
+    pub name: String
+}
 
 pub struct VESC {
     can_sender: CanSender,
@@ -23,7 +33,13 @@ impl VESC {
         if SETTINGS.get::<bool>("vesc.enabled").unwrap() {
             info!("VESC enabled!");
             
-            tokio::spawn(VescReadThread::start(self.can_receiver.clone(), self.metric_sender.clone()));
+            let controllers = SETTINGS.get::<Vec<VescController>>("vesc.controllers").unwrap();
+            tokio::spawn(VescReadThread::start(self.can_receiver.clone(), self.metric_sender.clone(), controllers.clone()));
+
+            // Fault codes and firmware versions are only available through COMM packets
+            if SETTINGS.get::<u64>("vesc.poll_interval").unwrap() > 0 {
+                tokio::spawn(VescComm::start(self.can_sender.clone(), self.can_receiver.clone(), self.metric_sender.clone(), controllers));
+            }
         }
     }
 }
@@ -1,31 +1,78 @@
-use log::{debug, warn};
+use std::collections::HashMap;
+
+use log::warn;
 use num_traits::FromPrimitive;
 use socketcan::EmbeddedFrame;
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{MessageId, StringFloatMap};
 
 use crate::SETTINGS;
-use crate::{can::{CanReceiver, get_can_id}, helper::MetricSender};
+use crate::{can::{CanReceiver, get_can_id}, helper::{bus::BusExt, MetricSender, MetricSenderExt}};
 
 use super::can_messages::*;
+use super::VescController;
+
+struct ControllerState {
+    name: String,
+    // Last value of every global metric and when it was received
+    values: HashMap<MessageId, (f64, Instant)>
+}
 
 pub struct VescReadThread {
     can_receiver: CanReceiver,
     metric_sender: MetricSender,
-    vesc_id: u32,
+    controllers: HashMap<u32, ControllerState>,
+    // Values older than this (ms) are left out of the aggregates
+    timeout: u128,
 }
 
 // Read Methods
 impl VescReadThread {
-    pub async fn start(can_receiver: CanReceiver, metric_sender: MetricSender) {
-        let vesc_id = SETTINGS.get::<u32>("vesc.id").unwrap();
-        let thread = VescReadThread { can_receiver, metric_sender, vesc_id };
+    pub async fn start(can_receiver: CanReceiver, metric_sender: MetricSender, controllers: Vec<VescController>) {
+        let timeout = SETTINGS.get::<u64>("vesc.timeout").unwrap() as u128;
+        let controllers = controllers
+            .into_iter()
+            .map(|controller| (controller.id, ControllerState { name: controller.name, values: HashMap::new() }))
+            .collect();
+        let mut thread = VescReadThread { can_receiver, metric_sender, controllers, timeout };
         thread.start_receiving().await;
     }
 
-    async fn start_receiving(&self) {
-        let mut receiver = self.can_receiver.subscribe();
+    // Publishes the per controller values and the aggregate over all controllers with a recent value
+    fn send_values(&mut self, vesc_id: u32, values: Vec<(MessageId, f64)>) {
+        let now = Instant::now();
+        for (global, value) in values {
+            let (per_controller, aggregate) = match controller_metric(global) {
+                Some(metric) => metric,
+                None => continue
+            };
+
+            let controller = self.controllers.get_mut(&vesc_id).unwrap();
+            controller.values.insert(global, (value, now));
+            let items = HashMap::from([(controller.name.clone(), value as f32)]);
+            self.metric_sender.send_now(per_controller, Value::StringFloatMap(StringFloatMap { items })).unwrap();
+
+            let recent: Vec<f64> = self.controllers
+                .values()
+                .filter_map(|controller| controller.values.get(&global))
+                .filter(|(_value, ts)| ts.elapsed().as_millis() < self.timeout)
+                .map(|(value, _ts)| *value)
+                .collect();
+            let total = match aggregate {
+                Aggregate::Sum => recent.iter().sum(),
+                Aggregate::Max => recent.iter().cloned().fold(f64::MIN, f64::max),
+                Aggregate::Mean => recent.iter().sum::<f64>() / recent.len() as f64
+            };
+            self.metric_sender.send_now(global, global_value(global, total)).unwrap();
+        }
+    }
+
+    async fn start_receiving(&mut self) {
+        let mut receiver = self.can_receiver.subscribe_as("vesc");
 
         loop {
-            let frame = receiver.recv().await.unwrap();
+            let Some(frame) = receiver.recv().await else { break };
 
             // if frame.dlc() != 8 {
             //     // VESC Messages will always have 8 bytes
@@ -34,25 +81,32 @@ impl VescReadThread {
             // }
 
             let can_id = get_can_id(frame.id());
-            let is_vesc_id = can_id & 0xFF == self.vesc_id;
-            if !is_vesc_id {
+            let vesc_id = can_id & 0xFF;
+            if !self.controllers.contains_key(&vesc_id) {
                 continue;
             }
             
             let data = frame.data();
             let msg_id = can_id >> 8;
 
-            match VescMessageIds ::from_u32(msg_id) {
-                Some(VescMessageIds::SetDuty) => SetDutyMsg::parse_and_send(data, &self.metric_sender).await,
-                Some(VescMessageIds::Status1) => StatusMsg1::parse_and_send(data, &self.metric_sender).await,
-                Some(VescMessageIds::Status2) => StatusMsg2::parse_and_send(data, &self.metric_sender).await,
-                Some(VescMessageIds::Status3) => StatusMsg3::parse_and_send(data, &self.metric_sender).await,
-                Some(VescMessageIds::Status4) => StatusMsg4::parse_and_send(data, &self.metric_sender).await,
-                Some(VescMessageIds::Status5) => StatusMsg5::parse_and_send(data, &self.metric_sender).await,
+            let values = match VescMessageIds ::from_u32(msg_id) {
+                Some(VescMessageIds::SetDuty) => SetDutyMsg::from_can_data(data).values(),
+                Some(VescMessageIds::Status1) => StatusMsg1::from_can_data(data).values(),
+                Some(VescMessageIds::Status2) => StatusMsg2::from_can_data(data).values(),
+                Some(VescMessageIds::Status3) => StatusMsg3::from_can_data(data).values(),
+                Some(VescMessageIds::Status4) => StatusMsg4::from_can_data(data).values(),
+                Some(VescMessageIds::Status5) => StatusMsg5::from_can_data(data).values(),
+                // Commands to the controllers (e.g. from the throttle), seen again through the CAN loopback
+                Some(VescMessageIds::SetCurrent | VescMessageIds::SetCurrentBrake | VescMessageIds::SetRpm | VescMessageIds::SetPos
+                    | VescMessageIds::SetCurrentRel | VescMessageIds::SetCurrentBrakeRel | VescMessageIds::SetCurrentHandbrake | VescMessageIds::SetCurrentHandbrakeRel) => continue,
+                // COMM packet transport, handled by VescComm
+                Some(VescMessageIds::FillRxBuffer | VescMessageIds::FillRxBufferLong | VescMessageIds::ProcessRxBuffer | VescMessageIds::ProcessShortBuffer) => continue,
                 _ => {
                     warn!("Unknown VESC Message ID: {}", msg_id);
+                    continue;
                 }
-            }
+            };
+            self.send_values(vesc_id, values);
         }
     }
-}
\ No newline at end of file
+}
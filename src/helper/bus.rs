@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::debug;
+use tokio::sync::broadcast::{self, error::{RecvError, TryRecvError}};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{MessageId, StringFloatMap};
+
+use super::{MetricSender, MetricSenderExt};
+
+// Receiving side of the metric and CAN bus.
+// The bus keeps the last `capacity` messages, a subscriber that falls further behind loses the oldest ones.
+// Instead of ending the subscriber's task the lost messages are counted and published per subscriber.
+
+#[derive(Default)]
+struct SubscriberStats {
+    dropped: AtomicU64,
+    // Messages waiting in the bus for this subscriber after its last receive
+    backlog: AtomicUsize
+}
+
+struct Subscriber {
+    name: String,
+    stats: Arc<SubscriberStats>
+}
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<HashMap<u64, Subscriber>> = Mutex::new(HashMap::new());
+    // Dropped messages of ended subscribers per name, so the totals never go down (e.g. a client disconnecting).
+    // Only locked while holding SUBSCRIBERS.
+    static ref ENDED_DROPPED: Mutex<HashMap<String, u64>> = Mutex::new(HashMap::new());
+}
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct BusReceiver<T> {
+    id: u64,
+    name: String,
+    receiver: broadcast::Receiver<T>,
+    stats: Arc<SubscriberStats>
+}
+
+impl<T: Clone> BusReceiver<T> {
+    fn lagged(&self, count: u64) {
+        self.stats.dropped.fetch_add(count, Ordering::Relaxed);
+        debug!("Bus subscriber {} lagged behind, dropped {} messages", self.name, count);
+    }
+
+    // Waits for the next message, None once the bus is closed
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(msg) => {
+                    self.stats.backlog.store(self.receiver.len(), Ordering::Relaxed);
+                    return Some(msg);
+                },
+                Err(RecvError::Lagged(count)) => self.lagged(count),
+                Err(RecvError::Closed) => return None
+            }
+        }
+    }
+
+    // Next message if one is waiting
+    pub fn try_recv(&mut self) -> Option<T> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(msg) => {
+                    self.stats.backlog.store(self.receiver.len(), Ordering::Relaxed);
+                    return Some(msg);
+                },
+                Err(TryRecvError::Lagged(count)) => self.lagged(count),
+                Err(_) => return None
+            }
+        }
+    }
+}
+
+impl<T> Drop for BusReceiver<T> {
+    fn drop(&mut self) {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        subscribers.remove(&self.id);
+        let dropped = self.stats.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            *ENDED_DROPPED.lock().unwrap().entry(self.name.clone()).or_default() += dropped;
+        }
+    }
+}
+
+pub trait BusExt<T> {
+    // Subscribes to the bus, the name identifies the subscriber in the bus metrics
+    fn subscribe_as(&self, name: &str) -> BusReceiver<T>;
+}
+
+impl<T: Clone> BusExt<T> for broadcast::Sender<T> {
+    fn subscribe_as(&self, name: &str) -> BusReceiver<T> {
+        let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(SubscriberStats::default());
+        SUBSCRIBERS.lock().unwrap().insert(id, Subscriber { name: name.to_string(), stats: stats.clone() });
+        BusReceiver { id, name: name.to_string(), receiver: self.subscribe(), stats }
+    }
+}
+
+// Dropped messages and backlog per subscriber name, subscribers sharing a name are summed up.
+// Dropped is a total since start, including subscribers that ended.
+pub fn subscriber_stats() -> Vec<(String, u64, usize)> {
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    let mut stats: HashMap<String, (u64, usize)> = ENDED_DROPPED.lock().unwrap().iter()
+        .map(|(name, dropped)| (name.clone(), (*dropped, 0)))
+        .collect();
+    for subscriber in subscribers.values() {
+        let entry = stats.entry(subscriber.name.clone()).or_default();
+        entry.0 += subscriber.stats.dropped.load(Ordering::Relaxed);
+        entry.1 += subscriber.stats.backlog.load(Ordering::Relaxed);
+    }
+    stats.into_iter().map(|(name, (dropped, backlog))| (name, dropped, backlog)).collect()
+}
+
+// Publishes BUS_DROPPED and BUS_BACKLOG (StringFloatMap keyed by subscriber) every interval
+pub fn start_bus_stats(metric_sender: MetricSender, interval: Duration) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let stats = subscriber_stats();
+            let dropped = stats.iter().map(|(name, dropped, _backlog)| (name.clone(), *dropped as f32)).collect();
+            let backlog = stats.iter().map(|(name, _dropped, backlog)| (name.clone(), *backlog as f32)).collect();
+            let _ = metric_sender.send_now(MessageId::BusDropped, Value::StringFloatMap(StringFloatMap { items: dropped }));
+            let _ = metric_sender.send_now(MessageId::BusBacklog, Value::StringFloatMap(StringFloatMap { items: backlog }));
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dropped(name: &str) -> u64 {
+        subscriber_stats().into_iter().find(|(subscriber, _dropped, _backlog)| subscriber == name).map(|(_name, dropped, _backlog)| dropped).unwrap_or(0)
+    }
+
+    #[test]
+    fn dropped_total_survives_ended_subscribers() {
+        let (sender, _receiver) = broadcast::channel::<u32>(2);
+        let mut receiver = sender.subscribe_as("test_ended");
+        for value in 0..5 {
+            sender.send(value).unwrap();
+        }
+        assert_eq!(receiver.try_recv(), Some(3));
+        assert_eq!(dropped("test_ended"), 3);
+
+        drop(receiver);
+        assert_eq!(dropped("test_ended"), 3);
+    }
+}
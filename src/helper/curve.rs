@@ -0,0 +1,55 @@
+use serde::Deserialize;
+
+// Piecewise linear curve through (x, y) points, constant beyond the first and last point.
+// In the config a curve is a list of points, e.g. [[80.0, 1.0], [100.0, 0.0]]
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "Vec<[f32; 2]>")]
+pub struct Curve {
+    points: Vec<[f32; 2]>
+}
+
+impl From<Vec<[f32; 2]>> for Curve {
+    fn from(mut points: Vec<[f32; 2]>) -> Self {
+        points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        Curve { points }
+    }
+}
+
+impl Curve {
+    // NaN and infinite x give the last point, for the protection and fan curves that is the hottest one
+    pub fn eval(&self, x: f32) -> f32 {
+        let (first, last) = match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0
+        };
+        if !x.is_finite() || x >= last[0] {
+            return last[1];
+        }
+        if x <= first[0] {
+            return first[1];
+        }
+        let upper = self.points.iter().position(|point| point[0] >= x).unwrap();
+        let (a, b) = (self.points[upper - 1], self.points[upper]);
+        a[1] + (x - a[0]) / (b[0] - a[0]) * (b[1] - a[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_between_points() {
+        let curve = Curve::from(vec![[100.0, 0.0], [80.0, 1.0]]);
+        assert_eq!(curve.eval(20.0), 1.0);
+        assert_eq!(curve.eval(90.0), 0.5);
+        assert_eq!(curve.eval(120.0), 0.0);
+    }
+
+    #[test]
+    fn non_finite_input_gives_last_point() {
+        let curve = Curve::from(vec![[80.0, 1.0], [100.0, 0.0]]);
+        assert_eq!(curve.eval(f32::NAN), 0.0);
+        assert_eq!(curve.eval(f32::NEG_INFINITY), 0.0);
+    }
+}
@@ -0,0 +1,133 @@
+// Spherical earth approximations, good enough for the distances we sail
+// https://www.movable-type.co.uk/scripts/latlong.html
+pub const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64
+}
+
+impl GeoPoint {
+    pub fn new(lat: f64, lon: f64) -> Self {
+        GeoPoint { lat, lon }
+    }
+
+    // GpsPos and FusedPosition are sent as [lat, lon, ...]
+    pub fn from_floats(values: &[f32]) -> Option<Self> {
+        if values.len() < 2 {
+            return None;
+        }
+        Some(GeoPoint::new(values[0] as f64, values[1] as f64))
+    }
+
+    // Great circle distance in metres
+    pub fn distance_to(&self, other: &GeoPoint) -> f64 {
+        let phi1 = self.lat.to_radians();
+        let phi2 = other.lat.to_radians();
+        let d_phi = (other.lat - self.lat).to_radians();
+        let d_lambda = (other.lon - self.lon).to_radians();
+
+        let a = (d_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (d_lambda / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_M * a.sqrt().atan2((1.0 - a).sqrt())
+    }
+
+    // Initial bearing in degrees (0..360, clockwise from true north)
+    pub fn bearing_to(&self, other: &GeoPoint) -> f64 {
+        let phi1 = self.lat.to_radians();
+        let phi2 = other.lat.to_radians();
+        let d_lambda = (other.lon - self.lon).to_radians();
+
+        let y = d_lambda.sin() * phi2.cos();
+        let x = phi1.cos() * phi2.sin() - phi1.sin() * phi2.cos() * d_lambda.cos();
+        (y.atan2(x).to_degrees() + 360.0) % 360.0
+    }
+
+    // Signed distance in metres from the great circle path start -> end, positive when right of track
+    pub fn cross_track_distance(&self, start: &GeoPoint, end: &GeoPoint) -> f64 {
+        let d13 = start.distance_to(self) / EARTH_RADIUS_M;
+        let theta13 = start.bearing_to(self).to_radians();
+        let theta12 = start.bearing_to(end).to_radians();
+        (d13.sin() * (theta13 - theta12).sin()).asin() * EARTH_RADIUS_M
+    }
+
+    // Distance in metres from start along the path start -> end to the point closest to self
+    pub fn along_track_distance(&self, start: &GeoPoint, end: &GeoPoint) -> f64 {
+        let d13 = start.distance_to(self) / EARTH_RADIUS_M;
+        let xtd = self.cross_track_distance(start, end) / EARTH_RADIUS_M;
+        let theta13 = start.bearing_to(self).to_radians();
+        let theta12 = start.bearing_to(end).to_radians();
+        let sign = (theta12 - theta13).cos().signum();
+        sign * (d13.cos() / xtd.cos()).clamp(-1.0, 1.0).acos() * EARTH_RADIUS_M
+    }
+
+    pub fn midpoint(&self, other: &GeoPoint) -> GeoPoint {
+        GeoPoint::new((self.lat + other.lat) / 2.0, (self.lon + other.lon) / 2.0)
+    }
+}
+
+// Ray casting in lat/lon space, fine for polygons that do not span the antimeridian
+pub fn polygon_contains(polygon: &[GeoPoint], point: &GeoPoint) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for i in 0..polygon.len() {
+        let (a, b) = (&polygon[i], &polygon[j]);
+        if (a.lat > point.lat) != (b.lat > point.lat)
+            && point.lon < (b.lon - a.lon) * (point.lat - a.lat) / (b.lat - a.lat) + a.lon {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+// True if the segments p1 -> p2 and q1 -> q2 intersect (planar approximation)
+pub fn segments_intersect(p1: &GeoPoint, p2: &GeoPoint, q1: &GeoPoint, q2: &GeoPoint) -> bool {
+    let orientation = |a: &GeoPoint, b: &GeoPoint, c: &GeoPoint| {
+        ((b.lon - a.lon) * (c.lat - a.lat) - (b.lat - a.lat) * (c.lon - a.lon)).signum()
+    };
+    orientation(p1, p2, q1) != orientation(p1, p2, q2) && orientation(q1, q2, p1) != orientation(q1, q2, p2)
+}
+
+// WGS84 ellipsoid
+const WGS84_A: f64 = 6_378_137.0;
+const WGS84_E2: f64 = 6.694_379_990_14e-3;
+
+// Local tangent plane (east, north, up) anchored at an origin.
+// Uses the curvature radii at the origin, which stays well below GPS accuracy within a few kilometres.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalProjection {
+    pub origin: GeoPoint,
+    // Metres per radian
+    north_radius: f64,
+    east_radius: f64
+}
+
+impl LocalProjection {
+    pub fn new(origin: GeoPoint) -> Self {
+        let sin_lat = origin.lat.to_radians().sin();
+        let denom = 1.0 - WGS84_E2 * sin_lat * sin_lat;
+        let prime_vertical = WGS84_A / denom.sqrt();
+        let meridional = WGS84_A * (1.0 - WGS84_E2) / denom.powf(1.5);
+        LocalProjection {
+            origin,
+            north_radius: meridional,
+            east_radius: prime_vertical * origin.lat.to_radians().cos()
+        }
+    }
+
+    // Returns (east, north) in metres
+    pub fn to_local(&self, point: &GeoPoint) -> (f64, f64) {
+        (
+            (point.lon - self.origin.lon).to_radians() * self.east_radius,
+            (point.lat - self.origin.lat).to_radians() * self.north_radius
+        )
+    }
+
+    pub fn to_geo(&self, east: f64, north: f64) -> GeoPoint {
+        GeoPoint::new(
+            self.origin.lat + (north / self.north_radius).to_degrees(),
+            self.origin.lon + (east / self.east_radius).to_degrees()
+        )
+    }
+}
@@ -0,0 +1,28 @@
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+use wannsea_types::boat_core_message::Value;
+
+// The BNO085 samples are in a left handed frame. Every component converts them with these functions,
+// flipping Y, so roll, pitch, heading and the fused state all refer to the same right handed frame.
+
+fn floats(value: Option<&Value>) -> &[f32] {
+    match value {
+        Some(Value::Floats(floats)) => &floats.values,
+        _ => &[]
+    }
+}
+
+// IMU vectors are sent as [x, y, z]
+pub fn vector(value: Option<&Value>) -> Option<Vector3<f32>> {
+    match floats(value) {
+        [x, y, z, ..] => Some(Vector3::new(*x, -*y, *z)),
+        _ => None
+    }
+}
+
+// IMU quaternions are sent as [i, j, k, real], mirroring the rotation in Y negates its x and z components
+pub fn orientation(value: Option<&Value>) -> Option<UnitQuaternion<f32>> {
+    match floats(value) {
+        [i, j, k, real, ..] => Some(UnitQuaternion::from_quaternion(Quaternion::new(*real, -*i, *j, -*k))),
+        _ => None
+    }
+}
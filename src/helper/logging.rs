@@ -1,9 +1,9 @@
-use log::{debug, warn};
+use log::debug;
 
 use socketcan::EmbeddedFrame;
 use crate::{can::{CanReceiver, get_can_id}, SETTINGS};
 
-use super::MetricSender;
+use super::{bus::BusExt, MetricSender};
 
 pub struct Logger {
     can_receiver: CanReceiver,
@@ -12,22 +12,17 @@ pub struct Logger {
 
 impl Logger {
     pub async fn log_can(can_receiver: CanReceiver) {
-        let mut can_receiver = can_receiver.subscribe();
+        let mut can_receiver = can_receiver.subscribe_as("log_can");
         loop {
-            let frame = can_receiver.recv().await.unwrap();
+            let Some(frame) = can_receiver.recv().await else { break };
             debug!(target: "CAN", "ID: {:X?} LEN: {} DATA: {:X?}", get_can_id(frame.id()), frame.dlc(), frame.data());
         }
     }
     
     pub async fn log_metrics(metric_sender: MetricSender) {
-        let mut metric_receiver = metric_sender.subscribe();
-        loop { 
-            match metric_receiver.recv().await {
-                Ok(metric) => {
-                    debug!(target: "Metric", "{} {:?}", metric.id().as_str_name(), metric.value.unwrap());
-                },
-                Err(err) => warn!("Error while receiving from Metric Bus: {:?}", err),
-            }
+        let mut metric_receiver = metric_sender.subscribe_as("log_metrics");
+        while let Some(metric) = metric_receiver.recv().await {
+            debug!(target: "Metric", "{} {:?}", metric.id().as_str_name(), metric.value.unwrap());
         }
     }
 
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::SETTINGS;
+
+use super::{bus::BusExt, MetricSender};
+
+// Latest message of every MessageId seen on the metric bus.
+// StringFloatMap values (per controller metrics) are merged, so the cached map holds the last value of every key.
+// Keys not updated for bus.cache_key_timeout are dropped, e.g. a controller that stopped reporting.
+// Replaces the stored message of the same id, StringFloatMap values are merged key by key.
+// Returns true if the id was not stored before.
+pub fn merge_latest(latest: &mut HashMap<MessageId, BoatCoreMessage>, msg: BoatCoreMessage) -> bool {
+    let id = msg.id();
+    match latest.get_mut(&id) {
+        Some(cached) => {
+            if let (Some(Value::StringFloatMap(map)), Some(Value::StringFloatMap(cached_map))) = (&msg.value, cached.value.as_mut()) {
+                cached_map.items.extend(map.items.clone());
+                cached.timestamp = msg.timestamp;
+            } else {
+                *cached = msg;
+            }
+            false
+        },
+        None => {
+            latest.insert(id, msg);
+            true
+        }
+    }
+}
+
+#[derive(Default)]
+struct CacheState {
+    latest: HashMap<MessageId, BoatCoreMessage>,
+    // When each key of a cached StringFloatMap was last updated
+    key_updated: HashMap<(MessageId, String), Instant>
+}
+
+impl CacheState {
+    fn is_fresh(&self, id: MessageId, key: &str, key_timeout: Duration) -> bool {
+        self.key_updated.get(&(id, key.to_string())).is_some_and(|ts| ts.elapsed() < key_timeout)
+    }
+
+    // Copy of the cached message without expired map keys
+    fn fresh(&self, msg: &BoatCoreMessage, key_timeout: Duration) -> BoatCoreMessage {
+        let mut msg = msg.clone();
+        let id = msg.id();
+        if let Some(Value::StringFloatMap(map)) = msg.value.as_mut() {
+            map.items.retain(|key, _value| self.is_fresh(id, key, key_timeout));
+        }
+        msg
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricCache {
+    state: Arc<RwLock<CacheState>>,
+    key_timeout: Duration
+}
+
+impl MetricCache {
+    pub fn new() -> Self {
+        let key_timeout = Duration::from_millis(SETTINGS.get::<u64>("bus.cache_key_timeout").unwrap());
+        MetricCache { state: Arc::new(RwLock::new(CacheState::default())), key_timeout }
+    }
+
+    fn insert(&self, msg: BoatCoreMessage) {
+        let mut state = self.state.write().unwrap();
+        let id = msg.id();
+        if let Some(Value::StringFloatMap(map)) = &msg.value {
+            let now = Instant::now();
+            for key in map.items.keys() {
+                state.key_updated.insert((id, key.clone()), now);
+            }
+        }
+        merge_latest(&mut state.latest, msg);
+
+        // Drop expired keys of this id from the stored map as well
+        let expired = match state.latest.get(&id).and_then(|cached| cached.value.as_ref()) {
+            Some(Value::StringFloatMap(map)) => map.items.keys().filter(|key| !state.is_fresh(id, key, self.key_timeout)).cloned().collect(),
+            _ => Vec::new()
+        };
+        for key in expired {
+            state.key_updated.remove(&(id, key.clone()));
+            if let Some(Value::StringFloatMap(map)) = state.latest.get_mut(&id).and_then(|cached| cached.value.as_mut()) {
+                map.items.remove(&key);
+            }
+        }
+    }
+
+    pub fn get(&self, id: MessageId) -> Option<BoatCoreMessage> {
+        let state = self.state.read().unwrap();
+        state.latest.get(&id).map(|msg| state.fresh(msg, self.key_timeout))
+    }
+
+    pub fn snapshot(&self) -> Vec<BoatCoreMessage> {
+        let state = self.state.read().unwrap();
+        state.latest.values().map(|msg| state.fresh(msg, self.key_timeout)).collect()
+    }
+
+    pub fn start(&self, metric_sender: &MetricSender) {
+        let cache = self.clone();
+        let mut receiver = metric_sender.subscribe_as("metric_cache");
+        tokio::spawn(async move {
+            while let Some(msg) = receiver.recv().await {
+                cache.insert(msg);
+            }
+        });
+    }
+}
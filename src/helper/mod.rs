@@ -6,19 +6,30 @@ use wannsea_types::MessageId;
 use wannsea_types::BoatCoreMessage;
 use wannsea_types::boat_core_message::Value;
 
+pub mod bus;
 pub mod logging;
 pub mod serial_ext;
+pub mod geo;
+pub mod curve;
+pub mod imu_frame;
+pub mod metric_cache;
 pub type MetricSender = broadcast::Sender<BoatCoreMessage>;
 
 pub trait MetricSenderExt {
     fn send_now(&self, id: MessageId, value: Value) -> Result<usize, tokio::sync::broadcast::error::SendError<BoatCoreMessage>>;
+    // For metrics whose sample time is known, e.g. IMU reports timestamped at interrupt time
+    fn send_at(&self, id: MessageId, value: Value, timestamp: chrono::DateTime<chrono::Utc>) -> Result<usize, tokio::sync::broadcast::error::SendError<BoatCoreMessage>>;
 }
 
 impl MetricSenderExt for MetricSender {
     fn send_now(&self, id: MessageId, value: Value) -> Result<usize, tokio::sync::broadcast::error::SendError<BoatCoreMessage>> {
+        self.send_at(id, value, chrono::Utc::now())
+    }
+
+    fn send_at(&self, id: MessageId, value: Value, timestamp: chrono::DateTime<chrono::Utc>) -> Result<usize, tokio::sync::broadcast::error::SendError<BoatCoreMessage>> {
         let mut msg = wannsea_types::BoatCoreMessage::default();
         msg.set_id(id);
-        msg.timestamp = Some(pbjson_types::Timestamp::from(chrono::Utc::now()));
+        msg.timestamp = Some(pbjson_types::Timestamp::from(timestamp));
         msg.value = Some(value);
         self.send(msg)
     }
@@ -2,15 +2,17 @@ mod helper;
 mod can;
 mod transport;
 mod component;
-use component::{computed::{power::MotorPower, sensor_fusion::SensorFusion}, gps::GPS, imu::IMU, lte::LTE, pmu::PMU, system_stats::SystemStats, vesc::{self, VESC}};
+use component::{computed::{attitude::Attitude, efficiency::Efficiency, power::MotorPower, sensor_fusion::SensorFusion, water_speed::WaterSpeed}, gps::GPS, imu::IMU, lte::LTE, navigation::{anchor_watch::AnchorWatch, Navigation}, pmu::PMU, protection::Protection, system_stats::SystemStats, throttle::{cruise::CruiseControl, Throttle}, vesc::{self, VESC}};
 use config::Config;
 
 
-use helper::logging::Logger;
+use helper::{bus, logging::Logger, metric_cache::MetricCache};
 use simple_logger::SimpleLogger;
 use lazy_static::lazy_static;
+use std::time::Duration;
+
 use tokio::{sync::broadcast, signal};
-use transport::web_socket_client::WebSocketClient;
+use transport::{influx_writer::InfluxWriter, mqtt_client::MqttClient, nmea::NmeaOutput, web_socket_client::WebSocketClient};
 use wannsea_types::BoatCoreMessage;
 use crate::{transport::web_socket_server::WebSocketServer, component::bms::BMS, can::CAN};
 lazy_static! {
@@ -29,21 +31,32 @@ async fn main() {
     println!("Starting Boat Core v{}", VERSION);
 
     // Metric bus
-    let (metric_sender, _metric_receiver) = broadcast::channel::<BoatCoreMessage>(64);
+    let (metric_sender, _metric_receiver) = broadcast::channel::<BoatCoreMessage>(SETTINGS.get::<usize>("bus.capacity").unwrap());
+    bus::start_bus_stats(metric_sender.clone(), Duration::from_millis(SETTINGS.get::<u64>("bus.stats_interval").unwrap()));
 
     let can = CAN::start();
 
     let logger = Logger::new(metric_sender.clone(), can.receiver.clone());
     logger.start();
 
-    let ws_server = WebSocketServer::new(metric_sender.clone());
+    // Latest value of every metric, for clients connecting later and components
+    let metric_cache = MetricCache::new();
+    metric_cache.start(&metric_sender);
+
+    let ws_server = WebSocketServer::new(metric_sender.clone(), metric_cache.clone());
     ws_server.start();
 
     let ws_client = WebSocketClient::new(metric_sender.clone());
     ws_client.start();
 
-    let sensor_fusion = SensorFusion::new(metric_sender.clone());
-    sensor_fusion.start();
+    let mqtt_client = MqttClient::new(metric_sender.clone());
+    mqtt_client.start();
+
+    let influx_writer = InfluxWriter::new(metric_sender.clone());
+    influx_writer.start();
+
+    let nmea_output = NmeaOutput::new(can.sender.clone(), can.receiver.clone(), metric_sender.clone());
+    nmea_output.start();
 
     let bms: BMS = BMS::new(can.sender.clone(), can.receiver.clone(), metric_sender.clone());
     bms.start();
@@ -51,7 +64,7 @@ async fn main() {
     let system_stats = SystemStats::new(metric_sender.clone());
     system_stats.start();
 
-    let pmu = PMU::new(can.receiver.clone(), metric_sender.clone());
+    let pmu = PMU::new(can.sender.clone(), can.receiver.clone(), metric_sender.clone());
     pmu.start();
 
     let gps = GPS::new(metric_sender.clone());
@@ -69,8 +82,32 @@ async fn main() {
     let vesc: VESC = VESC::new(can.sender.clone(), can.receiver.clone(), metric_sender.clone());
     vesc.start();
 
+    let protection: Protection = Protection::new(metric_sender.clone());
+    protection.start();
+
+    let throttle: Throttle = Throttle::new(can.sender.clone(), can.receiver.clone(), metric_sender.clone());
+    throttle.start();
+
+    let cruise_control: CruiseControl = CruiseControl::new(metric_sender.clone());
+    cruise_control.start();
+
     let motor_power: MotorPower = MotorPower::new(metric_sender.clone());
     motor_power.start();
 
+    let attitude: Attitude = Attitude::new(metric_sender.clone());
+    attitude.start();
+
+    let water_speed: WaterSpeed = WaterSpeed::new(can.receiver.clone(), metric_sender.clone());
+    water_speed.start();
+
+    let efficiency: Efficiency = Efficiency::new(metric_sender.clone());
+    efficiency.start();
+
+    let navigation: Navigation = Navigation::new(metric_sender.clone());
+    navigation.start();
+
+    let anchor_watch: AnchorWatch = AnchorWatch::new(metric_sender.clone());
+    anchor_watch.start();
+
     signal::ctrl_c().await.unwrap();
 }
\ No newline at end of file
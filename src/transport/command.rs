@@ -0,0 +1,58 @@
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::SETTINGS;
+
+// Messages remote clients may put on the metric bus, with the value type the receiving component expects.
+// Anything else (sensor values, limits, messages without a value) would be taken for real data by the
+// components and is rejected. Motor commands are only accepted from clients authenticated with the
+// throttle.remote_token, without a token they can only come from local components.
+#[derive(PartialEq)]
+enum ValueType {
+    Uint32,
+    Float
+}
+
+const COMMANDS: &[(MessageId, ValueType, bool)] = &[
+    (MessageId::AnchorWatchCommand, ValueType::Uint32, false),
+    (MessageId::MobCommand, ValueType::Uint32, false),
+    // Stores the BNO085 calibration, the value is ignored
+    (MessageId::ImuSaveCalibration, ValueType::Uint32, false),
+    (MessageId::ThrottleCommand, ValueType::Float, true),
+    (MessageId::CruiseSpeedCommand, ValueType::Float, true),
+    (MessageId::CruisePowerCommand, ValueType::Float, true)
+];
+
+fn value_type(value: &Value) -> Option<ValueType> {
+    match value {
+        Value::Uint32(_) => Some(ValueType::Uint32),
+        Value::Float(_) => Some(ValueType::Float),
+        _ => None
+    }
+}
+
+// Compares the token a client presented with throttle.remote_token, an empty token never matches
+pub fn is_authenticated(token: Option<&str>) -> bool {
+    let expected = SETTINGS.get::<String>("throttle.remote_token").unwrap_or_default();
+    match token {
+        Some(token) if !expected.is_empty() && token.len() == expected.len() => {
+            // Same time for every wrong token
+            token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+        },
+        _ => false
+    }
+}
+
+pub fn check(msg: &BoatCoreMessage, authenticated: bool) -> Result<(), String> {
+    let id = msg.id();
+    let Some((_id, expected, motor)) = COMMANDS.iter().find(|(command, _value_type, _motor)| *command == id) else {
+        return Err(format!("{} is not a command", id.as_str_name()));
+    };
+    if *motor && !authenticated {
+        return Err(format!("{} needs the remote token", id.as_str_name()));
+    }
+    match msg.value.as_ref().and_then(value_type) {
+        Some(value_type) if value_type == *expected => Ok(()),
+        _ => Err(format!("Unexpected value for {}", id.as_str_name()))
+    }
+}
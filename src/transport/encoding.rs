@@ -0,0 +1,56 @@
+use prost::Message as _;
+use tokio_tungstenite::tungstenite::Message;
+use wannsea_types::BoatCoreMessage;
+
+// Wire format of a WebSocket client, chosen with ?encoding= or the Sec-WebSocket-Protocol header.
+// JSON is sent as text frames, protobuf (same as the uplink) and MessagePack as binary frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    Protobuf,
+    MessagePack
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "json" => Some(Encoding::Json),
+            "protobuf" | "proto" => Some(Encoding::Protobuf),
+            "msgpack" | "messagepack" => Some(Encoding::MessagePack),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Json => "json",
+            Encoding::Protobuf => "protobuf",
+            Encoding::MessagePack => "msgpack"
+        }
+    }
+
+    pub fn encode(&self, msg: &BoatCoreMessage) -> Message {
+        match self {
+            Encoding::Json => Message::Text(serde_json::to_string(msg).unwrap()),
+            _ => Message::Binary(self.to_bytes(msg))
+        }
+    }
+
+    // Payload without WebSocket framing, e.g. for MQTT
+    pub fn to_bytes(&self, msg: &BoatCoreMessage) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(msg).unwrap(),
+            Encoding::Protobuf => msg.encode_to_vec(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(msg).unwrap()
+        }
+    }
+
+    // Commands sent by a client in binary frames
+    pub fn decode(&self, data: &[u8]) -> Result<BoatCoreMessage, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|err| err.to_string()),
+            Encoding::Protobuf => BoatCoreMessage::decode(data).map_err(|err| err.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(data).map_err(|err| err.to_string())
+        }
+    }
+}
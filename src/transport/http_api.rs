@@ -0,0 +1,247 @@
+use std::time::Duration;
+
+use log::{debug, warn};
+use serde_json::{json, Value as JsonValue};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::{SETTINGS, helper::{bus, metric_cache::MetricCache, MetricSender}};
+
+use super::{command, prometheus, signalk};
+
+// Plain HTTP requests on the ws-server listener, for dashboards and scripts that only need the current state.
+//   GET  /metrics          Prometheus exposition of every numeric metric
+//   GET  /metrics/latest   latest value of every metric
+//   GET  /metrics/{ID}     latest value of one metric, e.g. /metrics/BAT_VOLTAGE
+//   GET  /components       enabled flag of every component and the bus subscribers
+//   GET  /config           config with secrets redacted
+//   POST /commands/{ID}    puts the JSON body as value of ID on the metric bus, e.g. {"float": 0.5}. Needs
+//                          ws-server.commands, Content-Type application/json and an allowlisted command,
+//                          motor commands also Authorization: Bearer <throttle.remote_token>
+//   GET  /signalk          SignalK discovery, points apps to the delta stream
+
+const MAX_HEAD_SIZE: usize = 8192;
+const MAX_BODY_SIZE: usize = 65536;
+// For the head and again for the body, idle connections are closed afterwards
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct HttpRequest {
+    method: String,
+    path: String,
+    host: Option<String>,
+    content_type: Option<String>,
+    // Token of an Authorization: Bearer header
+    token: Option<String>,
+    head_len: usize,
+    content_length: usize
+}
+
+// Waits until the request head has arrived without consuming it, so a WebSocket handshake can still read it.
+// Returns the request and whether it asks for a WebSocket upgrade, None for invalid requests or after READ_TIMEOUT.
+pub async fn peek_request(stream: &TcpStream) -> Option<(HttpRequest, bool)> {
+    tokio::time::timeout(READ_TIMEOUT, peek_head(stream)).await.ok().flatten()
+}
+
+async fn peek_head(stream: &TcpStream) -> Option<(HttpRequest, bool)> {
+    let mut buf = vec![0u8; MAX_HEAD_SIZE];
+    for _ in 0..100 {
+        let len = stream.peek(&mut buf).await.ok()?;
+        if len == 0 {
+            return None;
+        }
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        match req.parse(&buf[..len]) {
+            Ok(httparse::Status::Complete(head_len)) => {
+                let header = |name: &str| req.headers.iter()
+                    .find(|header| header.name.eq_ignore_ascii_case(name))
+                    .and_then(|header| std::str::from_utf8(header.value).ok());
+                let upgrade = header("Upgrade").is_some_and(|value| value.eq_ignore_ascii_case("websocket"));
+                let content_length = header("Content-Length").and_then(|value| value.trim().parse().ok()).unwrap_or(0);
+                let request = HttpRequest {
+                    method: req.method?.to_string(),
+                    path: req.path?.to_string(),
+                    host: header("Host").map(String::from),
+                    content_type: header("Content-Type").map(String::from),
+                    token: header("Authorization").and_then(|value| value.strip_prefix("Bearer ")).map(|token| token.trim().to_string()),
+                    head_len,
+                    content_length
+                };
+                return Some((request, upgrade));
+            },
+            Ok(httparse::Status::Partial) if len < buf.len() => tokio::time::sleep(Duration::from_millis(10)).await,
+            _ => return None
+        }
+    }
+    None
+}
+
+fn redact(value: &mut JsonValue, keys: &[String]) {
+    match value {
+        JsonValue::Object(map) => {
+            for (name, value) in map.iter_mut() {
+                if keys.iter().any(|key| name.to_lowercase().contains(key.as_str())) {
+                    *value = JsonValue::String("<redacted>".to_string());
+                } else {
+                    redact(value, keys);
+                }
+            }
+        },
+        JsonValue::Array(values) => values.iter_mut().for_each(|value| redact(value, keys)),
+        _ => {}
+    }
+}
+
+fn config() -> JsonValue {
+    let mut config = SETTINGS.clone().try_deserialize::<JsonValue>().unwrap_or_default();
+    let keys: Vec<String> = SETTINGS.get::<Vec<String>>("ws-server.redact").unwrap().iter().map(|key| key.to_lowercase()).collect();
+    redact(&mut config, &keys);
+    config
+}
+
+// Every config section with an enabled flag is a component
+fn components() -> JsonValue {
+    let config = SETTINGS.clone().try_deserialize::<JsonValue>().unwrap_or_default();
+    let components: serde_json::Map<String, JsonValue> = config.as_object().into_iter().flatten()
+        .filter_map(|(name, section)| section.get("enabled").map(|enabled| (name.clone(), json!({ "enabled": enabled }))))
+        .collect();
+    let subscribers: serde_json::Map<String, JsonValue> = bus::subscriber_stats().into_iter()
+        .map(|(name, dropped, backlog)| (name, json!({ "dropped": dropped, "backlog": backlog })))
+        .collect();
+    json!({ "components": components, "subscribers": subscribers })
+}
+
+fn message_id(name: &str) -> Option<MessageId> {
+    MessageId::from_str_name(&name.to_uppercase())
+}
+
+// BoatCoreMessage is serialized with pbjson, its value is a field next to the id: {"id": "MOB_COMMAND", "uint32": 1}
+fn command_message(id: MessageId, body: &[u8]) -> Result<BoatCoreMessage, String> {
+    let JsonValue::Object(mut fields) = serde_json::from_slice(body).map_err(|err| err.to_string())? else {
+        return Err("Body must be a JSON object, e.g. {\"float\": 0.5}".to_string());
+    };
+    fields.insert("id".to_string(), JsonValue::String(id.as_str_name().to_string()));
+    serde_json::from_value(JsonValue::Object(fields)).map_err(|err| err.to_string())
+}
+
+// Status and error of a rejected command
+fn command(request: &HttpRequest, id: &str, body: &[u8], metric_bus: &MetricSender) -> Result<(), (u16, String)> {
+    if !SETTINGS.get::<bool>("ws-server.commands").unwrap() {
+        return Err((403, "Commands are disabled".to_string()));
+    }
+    // Other content types are sent cross-origin by browsers without a preflight
+    if !request.content_type.as_deref().is_some_and(|content_type| content_type.trim().starts_with(JSON)) {
+        return Err((415, format!("Content-Type must be {}", JSON)));
+    }
+    let id = message_id(id).ok_or_else(|| (400, format!("Unknown metric {}", id)))?;
+    let mut msg = command_message(id, body).map_err(|err| (400, err))?;
+    command::check(&msg, command::is_authenticated(request.token.as_deref())).map_err(|err| (403, err))?;
+    msg.timestamp = Some(pbjson_types::Timestamp::from(chrono::Utc::now()));
+    metric_bus.send(msg).map_err(|err| (500, err.to_string()))?;
+    Ok(())
+}
+
+const JSON: &str = "application/json";
+// Prometheus text exposition format
+const PROMETHEUS: &str = "text/plain; version=0.0.4";
+
+// Status, content type and body
+fn route(request: &HttpRequest, body: &[u8], metric_bus: &MetricSender, metric_cache: &MetricCache) -> (u16, &'static str, String) {
+    let path = request.path.split('?').next().unwrap_or_default().trim_end_matches('/');
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    if request.method == "GET" && segments == ["metrics"] {
+        let max_age = SETTINGS.get::<i64>("ws-server.prometheus_max_age").unwrap();
+        return (200, PROMETHEUS, prometheus::render(metric_cache, max_age));
+    }
+    let (status, json) = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["metrics", "latest"]) => {
+            (200, serde_json::to_value(metric_cache.snapshot()).unwrap())
+        },
+        ("GET", ["metrics", id]) => match message_id(id).and_then(|id| metric_cache.get(id)) {
+            Some(msg) => (200, serde_json::to_value(msg).unwrap()),
+            None => (404, json!({ "error": format!("No value for {}", id) }))
+        },
+        ("GET", ["components"]) => (200, components()),
+        ("GET", ["config"]) => (200, config()),
+        ("GET", ["signalk"]) if SETTINGS.get::<bool>("signalk.enabled").unwrap() => {
+            let host = request.host.clone().unwrap_or_else(|| SETTINGS.get::<String>("ws-server.address").unwrap());
+            (200, signalk::discovery(&host))
+        },
+        ("POST", ["commands", id]) => match command(request, id, body, metric_bus) {
+            Ok(_) => (202, json!({ "accepted": id.to_uppercase() })),
+            Err((status, err)) => (status, json!({ "error": err }))
+        },
+        _ => (404, json!({ "error": "Not found" }))
+    };
+    (status, JSON, json.to_string())
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        500 => "Internal Server Error",
+        _ => ""
+    }
+}
+
+// Answers a single request and closes the connection
+pub async fn handle_request(mut stream: TcpStream, request: HttpRequest, metric_bus: MetricSender, metric_cache: MetricCache) {
+    let (status, content_type, body) = if request.content_length > MAX_BODY_SIZE {
+        (413, JSON, json!({ "error": "Body too large" }).to_string())
+    } else {
+        let mut data = vec![0u8; request.head_len + request.content_length];
+        match tokio::time::timeout(READ_TIMEOUT, stream.read_exact(&mut data)).await {
+            Ok(Ok(_)) => route(&request, &data[request.head_len..], &metric_bus, &metric_cache),
+            Ok(Err(err)) => {
+                warn!("Error reading HTTP request {}: {}", request.path, err);
+                return;
+            },
+            Err(_elapsed) => (408, JSON, json!({ "error": "Timeout reading request" }).to_string())
+        }
+    };
+    debug!("HTTP {} {} -> {}", request.method, request.path, status);
+
+    // Dashboards in the browser may read, but other origins must not send commands
+    let cors = if request.method == "GET" { "Access-Control-Allow-Origin: *\r\n" } else { "" };
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        status, reason(status), content_type, body.len(), cors, body
+    );
+    if let Err(err) = stream.write_all(response.as_bytes()).await {
+        warn!("Error writing HTTP response: {}", err);
+    }
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use wannsea_types::boat_core_message::Value;
+
+    use super::*;
+
+    #[test]
+    fn command_body_is_the_message_value() {
+        let msg = command_message(MessageId::MobCommand, br#"{"uint32": 1}"#).unwrap();
+        assert_eq!(msg.id(), MessageId::MobCommand);
+        assert!(matches!(msg.value, Some(Value::Uint32(1))));
+        assert!(command::check(&msg, false).is_ok());
+
+        let msg = command_message(MessageId::CruiseSpeedCommand, br#"{"float": 0.5}"#).unwrap();
+        assert!(matches!(msg.value, Some(Value::Float(value)) if value == 0.5));
+        assert!(command::check(&msg, true).is_ok());
+    }
+
+    #[test]
+    fn command_body_must_be_an_object() {
+        assert!(command_message(MessageId::MobCommand, b"1").is_err());
+        let msg = command_message(MessageId::MobCommand, b"{}").unwrap();
+        assert!(command::check(&msg, false).is_err());
+    }
+}
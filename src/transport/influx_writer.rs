@@ -0,0 +1,215 @@
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, time::Instant};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::{helper::{bus::BusExt, MetricSender}, SETTINGS};
+
+use super::{metric_queue::MetricQueue, prometheus::map_label};
+
+// Writes all metrics as InfluxDB line protocol, either to the HTTP write API or appended to a file
+// for a later `influx write -f`. One line per metric with the MessageId as measurement:
+//   bat1_u1 value=3.71 1700000000123000000
+//   fused_velocity v0=1.2,v1=0.4,v2=0 1700000000123000000
+//   vesc_rpm,controller=motor1 value=1200 1700000000123000000
+// Messages are buffered in a MetricQueue and written in batches of batch_size or every flush_interval.
+pub struct InfluxWriter {
+    metric_sender: MetricSender,
+    cached_messages: MetricQueue<BoatCoreMessage>
+}
+
+// Rejected batches are dropped, as they would be rejected again, everything else is retried
+enum WriteError {
+    Rejected(String),
+    Failed(String)
+}
+
+enum Output {
+    Http { client: reqwest::Client, url: String, token: Option<String> },
+    File { path: String }
+}
+
+// Measurements, tag keys and tag values escape commas, spaces and equal signs (the latter not in measurements)
+fn escape_key(key: &str) -> String {
+    key.replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn escape_string(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn timestamp_ns(msg: &BoatCoreMessage) -> i64 {
+    match &msg.timestamp {
+        Some(ts) => ts.seconds * 1_000_000_000 + ts.nanos as i64,
+        None => chrono::Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    }
+}
+
+// Appends the lines of one message, nothing for values without a line protocol representation (bytes).
+// NaN and infinite floats are left out, InfluxDB rejects the whole batch for them.
+fn to_lines(out: &mut String, msg: &BoatCoreMessage, tags: &str) {
+    let Some(value) = &msg.value else { return };
+    let id = msg.id();
+    let measurement = id.as_str_name().to_lowercase();
+    let ts = timestamp_ns(msg);
+    let mut line = |extra_tag: Option<(&str, &str)>, fields: String| {
+        let _ = write!(out, "{}{}", measurement, tags);
+        if let Some((key, value)) = extra_tag {
+            let _ = write!(out, ",{}={}", key, escape_key(value));
+        }
+        let _ = writeln!(out, " {} {}", fields, ts);
+    };
+
+    match value {
+        Value::Float(v) if v.is_finite() => line(None, format!("value={}", v)),
+        Value::Double(v) if v.is_finite() => line(None, format!("value={}", v)),
+        Value::Int32(v) | Value::Sint32(v) => line(None, format!("value={}i", v)),
+        Value::Uint32(v) => line(None, format!("value={}i", v)),
+        // Signed like the other integers, InfluxDB 1.x has no unsigned type. Larger values would reject the batch.
+        Value::Uint64(v) => line(None, format!("value={}i", (*v).min(i64::MAX as u64))),
+        Value::String(v) => line(None, format!("value=\"{}\"", escape_string(v))),
+        Value::Floats(floats) => {
+            let fields: Vec<String> = floats.values.iter().enumerate()
+                .filter(|(_index, v)| v.is_finite())
+                .map(|(index, v)| format!("v{}={}", index, v))
+                .collect();
+            if !fields.is_empty() {
+                line(None, fields.join(","));
+            }
+        },
+        Value::StringFloatMap(map) => {
+            let label = map_label(id.as_str_name());
+            for (key, v) in map.items.iter().filter(|(_key, v)| v.is_finite()) {
+                line(Some((label, key)), format!("value={}", v));
+            }
+        },
+        _ => {}
+    }
+}
+
+impl Output {
+    // None for an unknown output, the writer stays disabled then
+    fn load() -> Option<Self> {
+        let output = match SETTINGS.get::<String>("influx.output").unwrap().as_str() {
+            "http" => Output::Http {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_millis(SETTINGS.get::<u64>("influx.timeout").unwrap()))
+                    .build()
+                    .unwrap(),
+                url: SETTINGS.get::<String>("influx.url").unwrap(),
+                token: SETTINGS.get::<String>("influx.token").ok().filter(|token| !token.is_empty())
+            },
+            "file" => Output::File { path: SETTINGS.get::<String>("influx.file").unwrap() },
+            output => {
+                error!("Unknown influx.output {}, InfluxDB writer disabled", output);
+                return None;
+            }
+        };
+        Some(output)
+    }
+
+    async fn write(&self, batch: &str) -> Result<(), WriteError> {
+        match self {
+            Output::Http { client, url, token } => {
+                let mut request = client.post(url).body(batch.to_string());
+                if let Some(token) = token {
+                    request = request.header("Authorization", format!("Token {}", token));
+                }
+                let response = request.send().await.map_err(|err| WriteError::Failed(err.to_string()))?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(());
+                }
+                // 4xx are invalid lines (e.g. a field type conflict), a bad token or a missing bucket
+                let err = format!("{}: {}", status, response.text().await.unwrap_or_default());
+                Err(if status.is_client_error() { WriteError::Rejected(err) } else { WriteError::Failed(err) })
+            },
+            Output::File { path } => {
+                if let Some(dir) = std::path::Path::new(path).parent() {
+                    tokio::fs::create_dir_all(dir).await.map_err(|err| WriteError::Failed(err.to_string()))?;
+                }
+                let mut file = OpenOptions::new().create(true).append(true).open(path).await.map_err(|err| WriteError::Failed(err.to_string()))?;
+                file.write_all(batch.as_bytes()).await.map_err(|err| WriteError::Failed(err.to_string()))
+            }
+        }
+    }
+}
+
+impl InfluxWriter {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        let cached_messages = MetricQueue::with_stat_ids(metric_sender.clone(), [MessageId::InfluxQueueCount, MessageId::InfluxInPerSec, MessageId::InfluxOutPerSec]);
+        InfluxWriter { metric_sender, cached_messages }
+    }
+
+    // Global tags from influx.tags, e.g. ",boat=wannsea"
+    fn tags() -> String {
+        let mut tags: Vec<(String, String)> = SETTINGS.get::<std::collections::HashMap<String, String>>("influx.tags").unwrap_or_default().into_iter().collect();
+        // Sorted tags are faster to ingest
+        tags.sort();
+        tags.iter().map(|(key, value)| format!(",{}={}", escape_key(key), escape_key(value))).collect()
+    }
+
+    async fn write_thread(output: Output, metric_queue: MetricQueue<BoatCoreMessage>) {
+        let tags = Self::tags();
+        let batch_size = SETTINGS.get::<usize>("influx.batch_size").unwrap();
+        let flush_interval = Duration::from_millis(SETTINGS.get::<u64>("influx.flush_interval").unwrap());
+        let retry_timeout = Duration::from_millis(SETTINGS.get::<u64>("influx.retry_timeout").unwrap());
+
+        loop {
+            // The first message starts the batch, it is written once full or flush_interval later
+            let mut batch = String::new();
+            let Some(first) = metric_queue.pop().await else { break };
+            to_lines(&mut batch, &first, &tags);
+            let mut count = 1;
+            let deadline = Instant::now() + flush_interval;
+            while count < batch_size {
+                match tokio::time::timeout_at(deadline, metric_queue.pop()).await {
+                    Ok(Some(msg)) => {
+                        to_lines(&mut batch, &msg, &tags);
+                        count += 1;
+                    },
+                    Ok(None) | Err(_) => break
+                }
+            }
+
+            // Keep the batch until it is written, new metrics wait in the queue meanwhile
+            loop {
+                match output.write(&batch).await {
+                    Ok(_) => {
+                        debug!("Wrote {} metrics to InfluxDB", count);
+                        break;
+                    },
+                    Err(WriteError::Rejected(err)) => {
+                        error!("InfluxDB rejected {} metrics, dropping them: {}", count, err);
+                        break;
+                    },
+                    Err(WriteError::Failed(err)) => {
+                        warn!("Could not write {} metrics to InfluxDB: {}. Retrying in {} ms...", count, err, retry_timeout.as_millis());
+                        tokio::time::sleep(retry_timeout).await;
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("influx.enabled").unwrap() {
+            let Some(output) = Output::load() else { return };
+            info!("InfluxDB Writer enabled!");
+
+            tokio::spawn(Self::write_thread(output, self.cached_messages.clone()));
+
+            let metric_sender = self.metric_sender.clone();
+            let metric_queue = self.cached_messages.clone();
+            tokio::spawn(async move {
+                let mut receiver = metric_sender.subscribe_as("influx_writer");
+                while let Some(msg) = receiver.recv().await {
+                    metric_queue.push(msg).await;
+                }
+            });
+        }
+    }
+}
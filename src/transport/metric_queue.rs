@@ -22,17 +22,25 @@ pub struct MetricQueue<T> {
     metric_sender: MetricSender,
     sender: UnboundedSender<T>,
     receiver: Arc<Mutex<UnboundedReceiver<T>>>,
-    stats: Arc<RwLock<MetricStats>>
+    stats: Arc<RwLock<MetricStats>>,
+    // Ids the queue length, in and out rate are published with
+    stat_ids: [MessageId; 3]
 }
 
 impl<T> MetricQueue<T> {
     pub fn new(metric_sender: MetricSender) -> Self {
+        Self::with_stat_ids(metric_sender, [MessageId::TxQueueCount, MessageId::TxInPerSec, MessageId::TxOutPerSec])
+    }
+
+    // For queues besides the uplink, so their stats do not mix with TX_*
+    pub fn with_stat_ids(metric_sender: MetricSender, stat_ids: [MessageId; 3]) -> Self {
         let (sender, receiver) = unbounded_channel();
         Self {
             metric_sender,
             sender: sender,
             receiver: Arc::new(Mutex::new(receiver)),
-            stats: Arc::new(RwLock::new(MetricStats { len: 0, last_ts: 0, metrics_in_per_sec: 0.0, metrics_out_per_sec: 0.0, metrics_in: 0, metrics_out: 0 }))
+            stats: Arc::new(RwLock::new(MetricStats { len: 0, last_ts: 0, metrics_in_per_sec: 0.0, metrics_out_per_sec: 0.0, metrics_in: 0, metrics_out: 0 })),
+            stat_ids
         }
     }
 
@@ -44,9 +52,10 @@ impl<T> MetricQueue<T> {
             stats.metrics_in = 0;
             stats.metrics_out = 0;
             stats.last_ts = ts;
-            self.metric_sender.send_now(MessageId::TxQueueCount, Value::Uint64(stats.len as u64)).unwrap();
-            self.metric_sender.send_now(MessageId::TxInPerSec, Value::Float(stats.metrics_in_per_sec as f32)).unwrap();
-            self.metric_sender.send_now(MessageId::TxOutPerSec, Value::Float(stats.metrics_out_per_sec as f32)).unwrap();
+            let [count_id, in_id, out_id] = self.stat_ids;
+            self.metric_sender.send_now(count_id, Value::Uint64(stats.len as u64)).unwrap();
+            self.metric_sender.send_now(in_id, Value::Float(stats.metrics_in_per_sec as f32)).unwrap();
+            self.metric_sender.send_now(out_id, Value::Float(stats.metrics_out_per_sec as f32)).unwrap();
         }
     }
 
@@ -58,16 +67,17 @@ impl<T> MetricQueue<T> {
         self.calc_stats(stats);
     }
 
-    pub async fn pop(&self) -> T {
+    // Waits for the next element, None once the queue is closed
+    pub async fn pop(&self) -> Option<T> {
         let receiver = self.receiver.clone();
 
-        let result = receiver.lock().await.recv().await.unwrap();
+        let result = receiver.lock().await.recv().await?;
 
         let mut stats = self.stats.write().unwrap();
         stats.len -= 1;
         stats.metrics_out += 1;
         self.calc_stats(stats);
-        return result;
+        Some(result)
     }
 }
 
@@ -77,7 +87,8 @@ impl<T> Clone for MetricQueue<T> {
             metric_sender: self.metric_sender.clone(),
             sender: self.sender.clone(),
             receiver: self.receiver.clone(),
-            stats: self.stats.clone()
+            stats: self.stats.clone(),
+            stat_ids: self.stat_ids
         }
     }
 }
\ No newline at end of file
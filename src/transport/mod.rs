@@ -1,3 +1,12 @@
 pub mod web_socket_server;
 pub mod web_socket_client;
-pub mod metric_queue;
\ No newline at end of file
+pub mod mqtt_client;
+pub mod influx_writer;
+pub mod nmea;
+pub mod metric_queue;
+pub mod subscription;
+pub mod encoding;
+pub mod command;
+pub mod http_api;
+pub mod prometheus;
+pub mod signalk;
@@ -0,0 +1,146 @@
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+use rumqttc::{AsyncClient, Event, EventLoop, LastWill, MqttOptions, Packet, QoS};
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::{helper::{bus::BusExt, MetricSender}, SETTINGS};
+
+use super::{command, encoding::Encoding, metric_queue::MetricQueue};
+
+// Publishes every metric to <topic_prefix>/<group>/<name>, derived from the MessageId: BAT1_U1 -> wannsea/boat/bat1/u1.
+// Messages go through a MetricQueue, so nothing is lost while the broker is unreachable, and are sent with QoS 1
+// and retained, so a new subscriber gets the last value right away. The queue is in memory, metrics that were
+// not yet published are lost on a restart.
+// Commands are received on the command topic as BoatCoreMessage in the configured encoding. Anyone who can publish
+// on the broker can send them, so only allowlisted commands without motor commands are taken, and retained ones
+// are ignored, as they would be replayed on every (re)subscribe.
+pub struct MqttClient {
+    metric_sender: MetricSender,
+    cached_messages: MetricQueue<BoatCoreMessage>
+}
+
+#[derive(Clone)]
+struct MqttConfig {
+    topic_prefix: String,
+    command_topic: String,
+    status_topic: String,
+    encoding: Encoding,
+    retain: bool
+}
+
+impl MqttConfig {
+    // None for an invalid config, the transport stays disabled then
+    fn load() -> Option<Self> {
+        let topic_prefix = SETTINGS.get::<String>("mqtt.topic_prefix").unwrap().trim_end_matches('/').to_string();
+        let encoding_name = SETTINGS.get::<String>("mqtt.encoding").unwrap();
+        let Some(encoding) = Encoding::from_name(&encoding_name) else {
+            error!("Unknown mqtt.encoding {}, MQTT client disabled", encoding_name);
+            return None;
+        };
+        Some(MqttConfig {
+            command_topic: format!("{}/command", topic_prefix),
+            status_topic: format!("{}/status", topic_prefix),
+            topic_prefix,
+            encoding,
+            retain: SETTINGS.get::<bool>("mqtt.retain").unwrap()
+        })
+    }
+
+    fn topic(&self, id: MessageId) -> String {
+        let name = id.as_str_name().to_lowercase();
+        match name.split_once('_') {
+            Some((group, rest)) => format!("{}/{}/{}", self.topic_prefix, group, rest),
+            None => format!("{}/{}", self.topic_prefix, name)
+        }
+    }
+}
+
+impl MqttClient {
+    pub fn new(metric_sender: MetricSender) -> Self {
+        let cached_messages = MetricQueue::with_stat_ids(metric_sender.clone(), [MessageId::MqttQueueCount, MessageId::MqttInPerSec, MessageId::MqttOutPerSec]);
+        MqttClient { metric_sender, cached_messages }
+    }
+
+    fn options(config: &MqttConfig) -> MqttOptions {
+        let mut options = MqttOptions::new(
+            SETTINGS.get::<String>("mqtt.client_id").unwrap(),
+            SETTINGS.get::<String>("mqtt.host").unwrap(),
+            SETTINGS.get::<u16>("mqtt.port").unwrap()
+        );
+        options.set_keep_alive(Duration::from_secs(SETTINGS.get::<u64>("mqtt.keep_alive").unwrap()));
+        // Keep the session on the broker, so QoS 1 messages in flight are completed after a reconnect
+        options.set_clean_session(false);
+        options.set_last_will(LastWill::new(&config.status_topic, "offline", QoS::AtLeastOnce, true));
+        if let (Ok(username), Ok(password)) = (SETTINGS.get::<String>("mqtt.username"), SETTINGS.get::<String>("mqtt.password")) {
+            options.set_credentials(username, password);
+        }
+        options
+    }
+
+    async fn publish_thread(client: AsyncClient, config: MqttConfig, metric_queue: MetricQueue<BoatCoreMessage>) {
+        while let Some(msg) = metric_queue.pop().await {
+            let topic = config.topic(msg.id());
+            // Waits while the client's request buffer is full (broker unreachable), the rest stays in the queue
+            if let Err(err) = client.publish(topic, QoS::AtLeastOnce, config.retain, config.encoding.to_bytes(&msg)).await {
+                warn!("MQTT publish failed: {}", err);
+                metric_queue.push(msg).await;
+            }
+        }
+    }
+
+    async fn event_thread(client: AsyncClient, mut event_loop: EventLoop, config: MqttConfig, metric_sender: MetricSender) {
+        let retry_timeout = Duration::from_millis(SETTINGS.get::<u64>("mqtt.retry_timeout").unwrap());
+        loop {
+            match event_loop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!("MQTT connected");
+                    if let Err(err) = client.try_subscribe(&config.command_topic, QoS::AtLeastOnce) {
+                        warn!("Could not subscribe to {}: {}", config.command_topic, err);
+                    }
+                    let _ = client.try_publish(&config.status_topic, QoS::AtLeastOnce, true, "online");
+                },
+                Ok(Event::Incoming(Packet::Publish(publish))) if publish.topic == config.command_topic => {
+                    if publish.retain {
+                        warn!("Ignoring retained MQTT command");
+                        continue;
+                    }
+                    match config.encoding.decode(&publish.payload) {
+                        Ok(msg) => match command::check(&msg, false) {
+                            Ok(_) => {
+                                let _ = metric_sender.send(msg);
+                            },
+                            Err(err) => warn!("Rejected MQTT command: {}", err)
+                        },
+                        Err(err) => warn!("Invalid MQTT command: {}", err)
+                    }
+                },
+                Ok(_event) => {},
+                Err(err) => {
+                    debug!("MQTT connection error: {}. Retrying in {} ms...", err, retry_timeout.as_millis());
+                    tokio::time::sleep(retry_timeout).await;
+                }
+            }
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("mqtt.enabled").unwrap() {
+            let Some(config) = MqttConfig::load() else { return };
+            info!("MQTT Client enabled!");
+
+            let (client, event_loop) = AsyncClient::new(Self::options(&config), SETTINGS.get::<usize>("mqtt.request_buffer").unwrap());
+            tokio::spawn(Self::event_thread(client.clone(), event_loop, config.clone(), self.metric_sender.clone()));
+            tokio::spawn(Self::publish_thread(client, config, self.cached_messages.clone()));
+
+            let metric_sender = self.metric_sender.clone();
+            let metric_queue = self.cached_messages.clone();
+            tokio::spawn(async move {
+                let mut receiver = metric_sender.subscribe_as("mqtt_client");
+                while let Some(msg) = receiver.recv().await {
+                    metric_queue.push(msg).await;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,155 @@
+pub mod nmea0183;
+pub mod nmea2000;
+
+use std::time::Duration;
+
+use log::info;
+use tokio::time::Instant;
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::{can::{CanReceiver, CanSender, CAN}, helper::{bus::BusExt, MetricSender}, SETTINGS};
+
+// Output for standard marine electronics: NMEA 0183 sentences over UDP/TCP (e.g. OpenCPN on a tablet)
+// and NMEA 2000 PGNs on a CAN interface (helm display). Both are fed from the same navigation data.
+pub struct NmeaOutput {
+    can_sender: CanSender,
+    can_receiver: CanReceiver,
+    metric_sender: MetricSender
+}
+
+// Latest values in the units of NMEA 0183: degrees, knots, volts, amps, percent and °C
+#[derive(Default, Clone)]
+pub struct NavData {
+    // Latitude and longitude
+    pub position: Option<(f64, f64)>,
+    pub sog: Option<f32>,
+    pub cog: Option<f32>,
+    pub heading_true: Option<f32>,
+    pub heading_magnetic: Option<f32>,
+    // Magnetic variation, east positive
+    pub variation: Option<f32>,
+    pub battery_voltage: Option<f32>,
+    pub battery_current: Option<f32>,
+    pub battery_soc: Option<f32>,
+    pub battery_temp: Option<f32>,
+    // Motor shaft RPM
+    pub rpm: Option<f32>
+}
+
+// Values with the time they were received, older values are not sent
+#[derive(Default)]
+struct NavState {
+    position: Option<((f64, f64), Instant)>,
+    sog: Option<(f32, Instant)>,
+    cog: Option<(f32, Instant)>,
+    heading_true: Option<(f32, Instant)>,
+    heading_magnetic: Option<(f32, Instant)>,
+    variation: Option<(f32, Instant)>,
+    battery_voltage: Option<(f32, Instant)>,
+    battery_current: Option<(f32, Instant)>,
+    battery_soc: Option<(f32, Instant)>,
+    battery_temp: Option<(f32, Instant)>,
+    rpm: Option<(f32, Instant)>
+}
+
+fn fresh<T: Copy>(value: Option<(T, Instant)>, stale_timeout: Duration) -> Option<T> {
+    value.filter(|(_value, ts)| ts.elapsed() < stale_timeout).map(|(value, _ts)| value)
+}
+
+impl NavState {
+    fn update(&mut self, msg: BoatCoreMessage, fused: bool, pole_pairs: f32) {
+        let now = Instant::now();
+        match (msg.id(), msg.value) {
+            (MessageId::FusedPosition, Some(Value::Floats(pos))) if fused && pos.values.len() >= 2 => {
+                self.position = Some(((pos.values[0] as f64, pos.values[1] as f64), now));
+            },
+            (MessageId::GpsPos, Some(Value::Floats(pos))) if !fused && pos.values.len() >= 2 => {
+                self.position = Some(((pos.values[0] as f64, pos.values[1] as f64), now));
+            },
+            (MessageId::FusedVelocity, Some(Value::Floats(velocity))) if fused && velocity.values.len() >= 2 => {
+                // East and north in m/s
+                let (east, north) = (velocity.values[0], velocity.values[1]);
+                self.sog = Some(((east.powi(2) + north.powi(2)).sqrt() * 3600.0 / 1852.0, now));
+                self.cog = Some((east.atan2(north).to_degrees().rem_euclid(360.0), now));
+            },
+            (MessageId::GpsSpeed, Some(Value::Double(speed))) if !fused => self.sog = Some((speed as f32, now)),
+            (MessageId::GpsCourse, Some(Value::Double(course))) if !fused => self.cog = Some((course as f32, now)),
+            (MessageId::GpsMagneticVariation, Some(Value::Double(variation))) => self.variation = Some((variation as f32, now)),
+            (MessageId::AttitudeHeadingTrue, Some(Value::Float(heading))) => self.heading_true = Some((heading, now)),
+            (MessageId::AttitudeHeadingMagnetic, Some(Value::Float(heading))) => self.heading_magnetic = Some((heading, now)),
+            (MessageId::EscInVoltage, Some(Value::Float(voltage))) => self.battery_voltage = Some((voltage, now)),
+            (MessageId::GlobalBatCurrent, Some(Value::Float(current))) => self.battery_current = Some((current, now)),
+            (MessageId::GlobalSoc, Some(Value::Uint32(soc))) => self.battery_soc = Some((soc as f32, now)),
+            (MessageId::BatTmax, Some(Value::Uint32(temp))) => self.battery_temp = Some((temp as f32, now)),
+            (MessageId::EscRpm, Some(Value::Int32(erpm))) => self.rpm = Some((erpm as f32 / pole_pairs, now)),
+            _ => {}
+        }
+    }
+
+    fn data(&self, stale_timeout: Duration) -> NavData {
+        NavData {
+            position: fresh(self.position, stale_timeout),
+            sog: fresh(self.sog, stale_timeout),
+            cog: fresh(self.cog, stale_timeout),
+            heading_true: fresh(self.heading_true, stale_timeout),
+            heading_magnetic: fresh(self.heading_magnetic, stale_timeout),
+            variation: fresh(self.variation, stale_timeout),
+            battery_voltage: fresh(self.battery_voltage, stale_timeout),
+            battery_current: fresh(self.battery_current, stale_timeout),
+            battery_soc: fresh(self.battery_soc, stale_timeout),
+            battery_temp: fresh(self.battery_temp, stale_timeout),
+            rpm: fresh(self.rpm, stale_timeout)
+        }
+    }
+}
+
+impl NmeaOutput {
+    pub fn new(can_sender: CanSender, can_receiver: CanReceiver, metric_sender: MetricSender) -> Self {
+        NmeaOutput { can_sender, can_receiver, metric_sender }
+    }
+
+    async fn run(metric_sender: MetricSender, n2k: Option<nmea2000::Nmea2000>) {
+        let interval = Duration::from_millis(SETTINGS.get::<u64>("nmea.interval").unwrap());
+        let stale_timeout = Duration::from_millis(SETTINGS.get::<u64>("nmea.stale_timeout").unwrap());
+        let fused = SETTINGS.get::<String>("nmea.position_source").unwrap() == MessageId::FusedPosition.as_str_name();
+        let pole_pairs = SETTINGS.get::<f32>("water_speed.motor_pole_pairs").unwrap();
+
+        let nmea0183 = nmea0183::Nmea0183::start().await;
+        let mut n2k = n2k;
+        let mut state = NavState::default();
+        let mut receiver = metric_sender.subscribe_as("nmea");
+        loop {
+            while let Some(msg) = receiver.try_recv() {
+                state.update(msg, fused, pole_pairs);
+            }
+
+            let data = state.data(stale_timeout);
+            if let Some(nmea0183) = &nmea0183 {
+                nmea0183.send(&data).await;
+            }
+            if let Some(n2k) = &mut n2k {
+                n2k.send(&data);
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    pub fn start(&self) {
+        if SETTINGS.get::<bool>("nmea.enabled").unwrap() {
+            info!("NMEA Output enabled!");
+
+            // The helm display usually sits on its own 250 kbit/s bus, the main bus is only used if configured
+            let n2k = match SETTINGS.get::<String>("nmea.n2k_interface").unwrap().as_str() {
+                "" => None,
+                interface if interface == SETTINGS.get::<String>("can.interface").unwrap() => Some(nmea2000::Nmea2000::start(self.can_sender.clone(), self.can_receiver.clone())),
+                interface => {
+                    let can = CAN::open(interface);
+                    Some(nmea2000::Nmea2000::start(can.sender, can.receiver))
+                }
+            };
+            tokio::spawn(Self::run(self.metric_sender.clone(), n2k));
+        }
+    }
+}
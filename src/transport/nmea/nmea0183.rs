@@ -0,0 +1,159 @@
+use chrono::{Timelike, Utc};
+use log::{debug, info, warn};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, UdpSocket}, sync::broadcast};
+
+use crate::{helper::bus::BusExt, SETTINGS};
+
+use super::NavData;
+
+// NMEA 0183 sentences, sent as UDP datagrams and to every client of a TCP server (OpenCPN: network connection, port 10110)
+//   RMC  position, SOG, COG and magnetic variation
+//   HDT  true heading, HDG magnetic heading
+//   XDR  battery voltage, current, state of charge and temperature
+//   RPM  motor RPM
+pub struct Nmea0183 {
+    talker: String,
+    udp: Option<(UdpSocket, String)>,
+    tcp: Option<broadcast::Sender<String>>
+}
+
+fn checksum(body: &str) -> u8 {
+    body.bytes().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+// Body is everything between $ and *
+fn sentence(body: String) -> String {
+    format!("${}*{:02X}\r\n", body, checksum(&body))
+}
+
+// ddmm.mmmm,N / dddmm.mmmm,E
+fn coordinate(value: f64, degree_digits: usize, positive: char, negative: char) -> String {
+    let abs = value.abs();
+    let mut degrees = abs.trunc();
+    // Rounded like the output, 59.99996 would be printed as 60.0000
+    let mut minutes = ((abs - degrees) * 60.0 * 10000.0).round() / 10000.0;
+    if minutes >= 60.0 {
+        degrees += 1.0;
+        minutes -= 60.0;
+    }
+    format!("{:0width$}{:07.4},{}", degrees as u32, minutes, if value >= 0.0 { positive } else { negative }, width = degree_digits)
+}
+
+fn optional(value: Option<f32>, decimals: usize) -> String {
+    value.map(|value| format!("{:.*}", decimals, value)).unwrap_or_default()
+}
+
+// Variation as absolute value and direction, e.g. 2.5,E
+fn variation(variation: Option<f32>) -> String {
+    match variation {
+        Some(variation) => format!("{:.1},{}", variation.abs(), if variation >= 0.0 { 'E' } else { 'W' }),
+        None => ",".to_string()
+    }
+}
+
+fn sentences(talker: &str, data: &NavData) -> String {
+    let mut out = String::new();
+    let now = Utc::now();
+
+    if let Some((lat, lon)) = data.position {
+        out += &sentence(format!(
+            "{}RMC,{:02}{:02}{:02}.{:02},A,{},{},{},{},{},{},A",
+            talker, now.hour(), now.minute(), now.second(), now.nanosecond() / 10_000_000,
+            coordinate(lat, 2, 'N', 'S'), coordinate(lon, 3, 'E', 'W'),
+            optional(data.sog, 1), optional(data.cog, 1), now.format("%d%m%y"), variation(data.variation)
+        ));
+    }
+    if let Some(heading) = data.heading_true {
+        out += &sentence(format!("{}HDT,{:.1},T", talker, heading));
+    }
+    if let Some(heading) = data.heading_magnetic {
+        out += &sentence(format!("{}HDG,{:.1},,,{}", talker, heading, variation(data.variation)));
+    }
+
+    let battery: Vec<String> = [
+        data.battery_voltage.map(|voltage| format!("U,{:.2},V,BAT1", voltage)),
+        data.battery_current.map(|current| format!("I,{:.1},A,BAT1", current)),
+        data.battery_soc.map(|soc| format!("G,{:.0},P,BAT1SOC", soc)),
+        data.battery_temp.map(|temp| format!("C,{:.1},C,BAT1", temp)),
+    ].into_iter().flatten().collect();
+    if !battery.is_empty() {
+        out += &sentence(format!("{}XDR,{}", talker, battery.join(",")));
+    }
+
+    if let Some(rpm) = data.rpm {
+        out += &sentence(format!("{}RPM,E,1,{:.0},,A", talker, rpm));
+    }
+    out
+}
+
+impl Nmea0183 {
+    async fn tcp_server(address: String, sender: broadcast::Sender<String>) {
+        let listener = match TcpListener::bind(&address).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("Could not listen for NMEA 0183 clients on {}: {}", address, err);
+                return;
+            }
+        };
+        info!("NMEA 0183 listening on: {}", address);
+        while let Ok((mut stream, addr)) = listener.accept().await {
+            debug!("NMEA 0183 client {} connected", addr);
+            let mut receiver = sender.subscribe_as("nmea_tcp");
+            tokio::spawn(async move {
+                while let Some(sentences) = receiver.recv().await {
+                    if stream.write_all(sentences.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+                debug!("NMEA 0183 client {} disconnected", addr);
+            });
+        }
+    }
+
+    // None if neither UDP nor TCP is configured
+    pub async fn start() -> Option<Self> {
+        let talker = SETTINGS.get::<String>("nmea.talker").unwrap();
+        let udp_target = SETTINGS.get::<String>("nmea.udp_target").unwrap();
+        let tcp_address = SETTINGS.get::<String>("nmea.tcp_address").unwrap();
+
+        let udp = match udp_target.as_str() {
+            "" => None,
+            target => match UdpSocket::bind("0.0.0.0:0").await.and_then(|socket| socket.set_broadcast(true).map(|_| socket)) {
+                Ok(socket) => Some((socket, target.to_string())),
+                Err(err) => {
+                    warn!("Could not open NMEA 0183 UDP socket: {}", err);
+                    None
+                }
+            }
+        };
+        let tcp = match tcp_address.as_str() {
+            "" => None,
+            address => {
+                let (sender, _receiver) = broadcast::channel(16);
+                tokio::spawn(Self::tcp_server(address.to_string(), sender.clone()));
+                Some(sender)
+            }
+        };
+
+        if udp.is_none() && tcp.is_none() {
+            return None;
+        }
+        Some(Nmea0183 { talker, udp, tcp })
+    }
+
+    pub async fn send(&self, data: &NavData) {
+        let sentences = sentences(&self.talker, data);
+        if sentences.is_empty() {
+            return;
+        }
+        if let Some((socket, target)) = &self.udp {
+            if let Err(err) = socket.send_to(sentences.as_bytes(), target).await {
+                debug!("Error sending NMEA 0183 to {}: {}", target, err);
+            }
+        }
+        if let Some(tcp) = &self.tcp {
+            // Fails only while no client is connected
+            let _ = tcp.send(sentences);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use log::{debug, info};
+use socketcan::{CanFrame, EmbeddedFrame, ExtendedId};
+
+use crate::{can::{get_can_id, CanReceiver, CanSender}, helper::bus::BusExt, SETTINGS};
+
+use super::NavData;
+
+// NMEA 2000 PGNs on CAN. All sent PGNs fit into a single frame, so no fast packet transport is needed.
+//   129025  Position, rapid update
+//   129026  COG & SOG, rapid update
+//   127250  Vessel heading
+//   127508  Battery status
+//   127488  Engine parameters, rapid update
+// The configured source address is claimed on start and on request. There is no negotiation on conflicts,
+// pick an address that is free on the bus.
+pub struct Nmea2000 {
+    can_sender: CanSender,
+    source: u8,
+    // Sequence id, ties together the PGNs of one update
+    sid: u8,
+    battery_instance: u8,
+    engine_instance: u8
+}
+
+const PGN_ISO_REQUEST: u32 = 59904;
+const PGN_ADDRESS_CLAIM: u32 = 60928;
+const PGN_POSITION_RAPID: u32 = 129025;
+const PGN_COG_SOG_RAPID: u32 = 129026;
+const PGN_VESSEL_HEADING: u32 = 127250;
+const PGN_BATTERY_STATUS: u32 = 127508;
+const PGN_ENGINE_RAPID: u32 = 127488;
+
+const BROADCAST: u8 = 0xFF;
+// Heading and COG reference
+const REFERENCE_TRUE: u8 = 0;
+const REFERENCE_MAGNETIC: u8 = 1;
+
+// 29 bit id: priority, PGN (for PDU1 PGNs the low byte is the destination), source address
+fn can_id(priority: u8, pgn: u32, source: u8) -> u32 {
+    ((priority as u32) << 26) | (pgn << 8) | source as u32
+}
+
+// Resolution 1e-4 rad, 0xFFFF is not available
+fn angle(degrees: Option<f32>) -> u16 {
+    degrees.map_or(0xFFFF, |degrees| (degrees.rem_euclid(360.0).to_radians() * 10_000.0).round() as u16)
+}
+
+fn signed_angle(degrees: Option<f32>) -> i16 {
+    degrees.map_or(0x7FFF, |degrees| (degrees.to_radians() * 10_000.0).round() as i16)
+}
+
+fn scaled_u16(value: Option<f32>, resolution: f32) -> u16 {
+    value.map_or(0xFFFF, |value| (value / resolution).round().clamp(0.0, 65533.0) as u16)
+}
+
+fn scaled_i16(value: Option<f32>, resolution: f32) -> i16 {
+    value.map_or(0x7FFF, |value| (value / resolution).round().clamp(-32767.0, 32765.0) as i16)
+}
+
+fn position_rapid(lat: f64, lon: f64) -> [u8; 8] {
+    let mut data = [0u8; 8];
+    data[0..4].copy_from_slice(&((lat * 1e7).round() as i32).to_le_bytes());
+    data[4..8].copy_from_slice(&((lon * 1e7).round() as i32).to_le_bytes());
+    data
+}
+
+fn cog_sog_rapid(sid: u8, data: &NavData) -> [u8; 8] {
+    let cog = angle(data.cog).to_le_bytes();
+    // Knots to m/s with 0.01 resolution
+    let sog = scaled_u16(data.sog.map(|sog| sog * 1852.0 / 3600.0), 0.01).to_le_bytes();
+    [sid, 0xFC | REFERENCE_TRUE, cog[0], cog[1], sog[0], sog[1], 0xFF, 0xFF]
+}
+
+fn vessel_heading(sid: u8, data: &NavData) -> Option<[u8; 8]> {
+    let (heading, reference) = match (data.heading_true, data.heading_magnetic) {
+        (Some(heading), _) => (heading, REFERENCE_TRUE),
+        (None, Some(heading)) => (heading, REFERENCE_MAGNETIC),
+        _ => return None
+    };
+    let heading = angle(Some(heading)).to_le_bytes();
+    let deviation = signed_angle(None).to_le_bytes();
+    let variation = signed_angle(data.variation).to_le_bytes();
+    Some([sid, heading[0], heading[1], deviation[0], deviation[1], variation[0], variation[1], 0xFC | reference])
+}
+
+fn battery_status(sid: u8, instance: u8, data: &NavData) -> [u8; 8] {
+    let voltage = scaled_i16(data.battery_voltage, 0.01).to_le_bytes();
+    let current = scaled_i16(data.battery_current, 0.1).to_le_bytes();
+    // °C to K with 0.01 resolution
+    let temp = scaled_u16(data.battery_temp.map(|temp| temp + 273.15), 0.01).to_le_bytes();
+    [instance, voltage[0], voltage[1], current[0], current[1], temp[0], temp[1], sid]
+}
+
+fn engine_rapid(instance: u8, rpm: f32) -> [u8; 8] {
+    let speed = scaled_u16(Some(rpm.abs()), 0.25).to_le_bytes();
+    // Boost pressure and tilt/trim not available
+    [instance, speed[0], speed[1], 0xFF, 0xFF, 0x7F, 0xFF, 0xFF]
+}
+
+// ISO NAME: unique number, manufacturer code, device function 130 (PC gateway), device class 25 (inter/intranetwork), industry group 4 (marine)
+fn name(unique_number: u32, manufacturer_code: u16) -> [u8; 8] {
+    let name: u64 = (unique_number as u64 & 0x1F_FFFF)
+        | ((manufacturer_code as u64 & 0x7FF) << 21)
+        | (130u64 << 40)
+        | (25u64 << 49)
+        | (4u64 << 60);
+    name.to_le_bytes()
+}
+
+impl Nmea2000 {
+    fn send_frame(can_sender: &CanSender, priority: u8, pgn: u32, source: u8, data: &[u8]) {
+        let frame = CanFrame::new(ExtendedId::new(can_id(priority, pgn, source)).unwrap(), data).unwrap();
+        if can_sender.send(frame).is_err() {
+            debug!("Error sending NMEA 2000 PGN {}", pgn);
+        }
+    }
+
+    // Answers ISO requests for the address claim, e.g. from a display that just powered up
+    async fn address_claim(can_sender: CanSender, can_receiver: CanReceiver, source: u8, name: [u8; 8]) {
+        Self::send_frame(&can_sender, 6, PGN_ADDRESS_CLAIM | BROADCAST as u32, source, &name);
+
+        let mut receiver = can_receiver.subscribe_as("nmea2000");
+        while let Some(frame) = receiver.recv().await {
+            let id = get_can_id(frame.id());
+            let pf = (id >> 16) & 0xFF;
+            let destination = ((id >> 8) & 0xFF) as u8;
+            let data = frame.data();
+            if frame.is_extended() && pf == PGN_ISO_REQUEST >> 8 && (destination == source || destination == BROADCAST)
+                && data.len() >= 3 && u32::from_le_bytes([data[0], data[1], data[2], 0]) == PGN_ADDRESS_CLAIM {
+                Self::send_frame(&can_sender, 6, PGN_ADDRESS_CLAIM | BROADCAST as u32, source, &name);
+            }
+        }
+    }
+
+    pub fn start(can_sender: CanSender, can_receiver: CanReceiver) -> Self {
+        let source = SETTINGS.get::<u8>("nmea.n2k_source_address").unwrap();
+        let name = name(SETTINGS.get::<u32>("nmea.n2k_unique_number").unwrap(), SETTINGS.get::<u16>("nmea.n2k_manufacturer_code").unwrap());
+        info!("NMEA 2000 output with source address {}", source);
+        tokio::spawn(Self::address_claim(can_sender.clone(), can_receiver, source, name));
+
+        Nmea2000 {
+            can_sender,
+            source,
+            sid: 0,
+            battery_instance: SETTINGS.get::<u8>("nmea.n2k_battery_instance").unwrap(),
+            engine_instance: SETTINGS.get::<u8>("nmea.n2k_engine_instance").unwrap()
+        }
+    }
+
+    pub fn send(&mut self, data: &NavData) {
+        // 0..252, the higher values are reserved
+        self.sid = (self.sid + 1) % 253;
+        let send = |priority: u8, pgn: u32, payload: &[u8]| Self::send_frame(&self.can_sender, priority, pgn, self.source, payload);
+
+        if let Some((lat, lon)) = data.position {
+            send(2, PGN_POSITION_RAPID, &position_rapid(lat, lon));
+        }
+        if data.sog.is_some() || data.cog.is_some() {
+            send(2, PGN_COG_SOG_RAPID, &cog_sog_rapid(self.sid, data));
+        }
+        if let Some(heading) = vessel_heading(self.sid, data) {
+            send(2, PGN_VESSEL_HEADING, &heading);
+        }
+        if data.battery_voltage.is_some() || data.battery_current.is_some() || data.battery_temp.is_some() {
+            send(6, PGN_BATTERY_STATUS, &battery_status(self.sid, self.battery_instance, data));
+        }
+        if let Some(rpm) = data.rpm {
+            send(2, PGN_ENGINE_RAPID, &engine_rapid(self.engine_instance, rpm));
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt::Write;
+
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::helper::{bus, metric_cache::MetricCache};
+
+// Prometheus text exposition of the metric cache, served as GET /metrics for Grafana on the boat network.
+// Every numeric metric becomes a gauge named wannsea_<message_id>. Pack, cell and controller are labels
+// instead of part of the name, e.g. BAT2_U7 -> wannsea_bat_u{pack="2",cell="7"}.
+
+const PREFIX: &str = "wannsea";
+
+struct Sample {
+    labels: Vec<(&'static str, String)>,
+    value: f64
+}
+
+// Label of the keys of a StringFloatMap metric
+pub fn map_label(name: &str) -> &'static str {
+    match name {
+        _ if name.starts_with("VESC_") => "controller",
+        _ if name.starts_with("PROC_") => "process",
+        _ if name.starts_with("BUS_") => "subscriber",
+        _ => "key"
+    }
+}
+
+// BAT<pack>_<rest>, with U<n> (cell voltage) and T<n> (temperature sensor) split into name and index
+fn split_name(name: &str) -> (String, Vec<(&'static str, String)>) {
+    let Some((pack, rest)) = name.strip_prefix("BAT").and_then(|rest| rest.split_once('_')) else {
+        return (name.to_lowercase(), Vec::new());
+    };
+    if pack.is_empty() || !pack.chars().all(|c| c.is_ascii_digit()) {
+        return (name.to_lowercase(), Vec::new());
+    }
+    let mut labels = vec![("pack", pack.to_string())];
+    let index_label = match rest.split_at(rest.len().min(1)) {
+        ("U", index) if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) => Some(("cell", index)),
+        ("T", index) if !index.is_empty() && index.chars().all(|c| c.is_ascii_digit()) => Some(("sensor", index)),
+        _ => None
+    };
+    match index_label {
+        Some((label, index)) => {
+            labels.push((label, index.to_string()));
+            (format!("bat_{}", rest[..1].to_lowercase()), labels)
+        },
+        None => (format!("bat_{}", rest.to_lowercase()), labels)
+    }
+}
+
+fn samples(name: &str, value: &Value) -> Vec<Sample> {
+    let single = |value: f64| vec![Sample { labels: Vec::new(), value }];
+    match value {
+        Value::Float(v) => single(*v as f64),
+        Value::Double(v) => single(*v),
+        Value::Int32(v) | Value::Sint32(v) => single(*v as f64),
+        Value::Uint32(v) => single(*v as f64),
+        Value::Uint64(v) => single(*v as f64),
+        Value::Floats(floats) => floats.values.iter().enumerate()
+            .map(|(index, v)| Sample { labels: vec![("index", index.to_string())], value: *v as f64 })
+            .collect(),
+        Value::StringFloatMap(map) => map.items.iter()
+            .map(|(key, v)| Sample { labels: vec![(map_label(name), key.clone())], value: *v as f64 })
+            .collect(),
+        // Strings and bytes have no numeric value
+        _ => Vec::new()
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &[(&str, String)], value: f64) {
+    let _ = write!(out, "{}", name);
+    if !labels.is_empty() {
+        let labels: Vec<String> = labels.iter().map(|(label, value)| format!("{}=\"{}\"", label, escape(value))).collect();
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    // Rust writes infinities as inf, the exposition format expects +Inf and -Inf (NaN is the same)
+    let value = match value {
+        f64::INFINITY => "+Inf".to_string(),
+        f64::NEG_INFINITY => "-Inf".to_string(),
+        value => value.to_string()
+    };
+    let _ = writeln!(out, " {}", value);
+}
+
+fn age_ms(msg: &BoatCoreMessage) -> i64 {
+    match &msg.timestamp {
+        Some(ts) => chrono::Utc::now().timestamp_millis() - (ts.seconds * 1000 + ts.nanos as i64 / 1_000_000),
+        None => 0
+    }
+}
+
+// Metrics older than max_age (ms, 0 keeps all) are left out so Grafana shows gaps instead of stale values
+pub fn render(metric_cache: &MetricCache, max_age: i64) -> String {
+    let mut messages = metric_cache.snapshot();
+    messages.sort_by_key(|msg| msg.id);
+
+    // Samples grouped by metric name, BAT1_U1 and BAT2_U1 end up in the same family
+    let mut families: Vec<(String, Vec<Sample>)> = Vec::new();
+    for msg in messages {
+        let id = msg.id();
+        // Published below as counters straight from the bus
+        if matches!(id, MessageId::BusDropped | MessageId::BusBacklog) || (max_age > 0 && age_ms(&msg) > max_age) {
+            continue;
+        }
+        let Some(value) = &msg.value else { continue };
+        let (name, labels) = split_name(id.as_str_name());
+        let mut samples = samples(id.as_str_name(), value);
+        if samples.is_empty() {
+            continue;
+        }
+        for sample in samples.iter_mut() {
+            sample.labels.splice(0..0, labels.iter().cloned());
+        }
+        match families.iter_mut().find(|(family, _samples)| *family == name) {
+            Some((_name, family)) => family.extend(samples),
+            None => families.push((name, samples))
+        }
+    }
+    families.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut out = String::new();
+    for (name, samples) in families {
+        let name = format!("{}_{}", PREFIX, name);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        for sample in samples {
+            write_sample(&mut out, &name, &sample.labels, sample.value);
+        }
+    }
+
+    let stats = bus::subscriber_stats();
+    let _ = writeln!(out, "# HELP {}_bus_dropped_total Messages a bus subscriber lost by lagging behind", PREFIX);
+    let _ = writeln!(out, "# TYPE {}_bus_dropped_total counter", PREFIX);
+    for (subscriber, dropped, _backlog) in &stats {
+        write_sample(&mut out, &format!("{}_bus_dropped_total", PREFIX), &[("subscriber", subscriber.clone())], *dropped as f64);
+    }
+    let _ = writeln!(out, "# HELP {}_bus_backlog Messages waiting in the bus for a subscriber", PREFIX);
+    let _ = writeln!(out, "# TYPE {}_bus_backlog gauge", PREFIX);
+    for (subscriber, _dropped, backlog) in &stats {
+        write_sample(&mut out, &format!("{}_bus_backlog", PREFIX), &[("subscriber", subscriber.clone())], *backlog as f64);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_samples() {
+        let mut out = String::new();
+        write_sample(&mut out, "a", &[], f64::INFINITY);
+        write_sample(&mut out, "b", &[], f64::NEG_INFINITY);
+        write_sample(&mut out, "c", &[], f64::NAN);
+        write_sample(&mut out, "d", &[], 1.5);
+        assert_eq!(out, "a +Inf\nb -Inf\nc NaN\nd 1.5\n");
+    }
+}
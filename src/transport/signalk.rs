@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{SinkExt, StreamExt};
+use log::{error, info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+use tokio::{net::TcpStream, sync::{watch, Notify}};
+use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use wannsea_types::boat_core_message::Value;
+use wannsea_types::{BoatCoreMessage, MessageId};
+
+use crate::{helper::{bus::BusExt, metric_cache::MetricCache, MetricSender}, SETTINGS};
+
+use super::subscription::Subscription;
+
+// SignalK delta stream on /signalk/v1/stream, so apps like KIP or Freeboard can show our data.
+// Metrics are mapped to SignalK paths in SI units (m/s, rad, K, ratio):
+//   FUSED_POSITION / GPS_POS                  navigation.position
+//   FUSED_VELOCITY / GPS_SPEED, GPS_COURSE    navigation.speedOverGround, navigation.courseOverGroundTrue
+//   ATTITUDE_*                                navigation.headingTrue, navigation.headingMagnetic, navigation.attitude
+//   WATER_SPEED                               navigation.speedThroughWater
+//   BAT<n>_U1..U14, BAT<n>_SOC, BAT<n>_SOH    electrical.batteries.<n>.voltage, .capacity.stateOfCharge, .capacity.stateOfHealth
+//   ESC_IN_VOLTAGE, GLOBAL_BAT_CURRENT, ...   electrical.batteries.main.*
+//   VESC_RPM, VESC_MOTOR_TEMP                 propulsion.<id>.revolutions, propulsion.<id>.temperature
+// Metrics without a SignalK path are not sent.
+
+pub const STREAM_PATH: &str = "/signalk/v1/stream";
+
+const KNOTS_TO_MS: f64 = 1852.0 / 3600.0;
+const CELSIUS_TO_KELVIN: f64 = 273.15;
+// BAT<n>_U1 .. BAT<n>_U14
+const CELLS_PER_PACK: usize = 14;
+
+pub struct SignalKMapper {
+    fused: bool,
+    pole_pairs: f64,
+    // SignalK propulsion id per VESC controller name, e.g. motor1 -> port
+    propulsion: HashMap<String, String>,
+    // Cell voltages in mV per pack, the pack voltage is their sum
+    cells: HashMap<String, [Option<u32>; CELLS_PER_PACK]>,
+    roll: Option<f64>
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Float(v) => Some(*v as f64),
+        Value::Double(v) => Some(*v),
+        Value::Int32(v) | Value::Sint32(v) => Some(*v as f64),
+        Value::Uint32(v) => Some(*v as f64),
+        Value::Uint64(v) => Some(*v as f64),
+        _ => None
+    }
+}
+
+impl SignalKMapper {
+    pub fn new() -> Self {
+        SignalKMapper {
+            fused: SETTINGS.get::<String>("signalk.position_source").unwrap() == MessageId::FusedPosition.as_str_name(),
+            pole_pairs: SETTINGS.get::<f64>("water_speed.motor_pole_pairs").unwrap(),
+            propulsion: SETTINGS.get::<HashMap<String, String>>("signalk.propulsion").unwrap_or_default(),
+            cells: HashMap::new(),
+            roll: None
+        }
+    }
+
+    // BAT<n>_<rest>
+    fn pack(&mut self, pack: &str, rest: &str, value: f64) -> Vec<(String, JsonValue)> {
+        let base = format!("electrical.batteries.{}", pack);
+        match rest {
+            "SOC" => vec![(format!("{}.capacity.stateOfCharge", base), json!(value / 100.0))],
+            "SOH" => vec![(format!("{}.capacity.stateOfHealth", base), json!(value / 100.0))],
+            _ => {
+                let Some(cell) = rest.strip_prefix('U').and_then(|cell| cell.parse::<usize>().ok()).filter(|cell| (1..=CELLS_PER_PACK).contains(cell)) else {
+                    return Vec::new();
+                };
+                let cells = self.cells.entry(pack.to_string()).or_default();
+                cells[cell - 1] = Some(value as u32);
+                // The last cell closes a round of voltage frames
+                match cells.iter().copied().sum::<Option<u32>>() {
+                    Some(millivolts) if cell == CELLS_PER_PACK => vec![(format!("{}.voltage", base), json!(millivolts as f64 / 1000.0))],
+                    _ => Vec::new()
+                }
+            }
+        }
+    }
+
+    // Per controller metrics, keyed by controller name
+    fn propulsion(&self, map: &HashMap<String, f32>, field: &str, convert: impl Fn(f64) -> f64) -> Vec<(String, JsonValue)> {
+        map.iter().map(|(controller, value)| {
+            let id = self.propulsion.get(controller).unwrap_or(controller);
+            (format!("propulsion.{}.{}", id, field), json!(convert(*value as f64)))
+        }).collect()
+    }
+
+    pub fn map(&mut self, msg: &BoatCoreMessage) -> Vec<(String, JsonValue)> {
+        let Some(value) = &msg.value else { return Vec::new() };
+        let id = msg.id();
+        let single = |path: &str, value: JsonValue| vec![(path.to_string(), value)];
+
+        match (id, value) {
+            (MessageId::FusedPosition, Value::Floats(pos)) if self.fused && pos.values.len() >= 2 => {
+                single("navigation.position", json!({ "latitude": pos.values[0], "longitude": pos.values[1] }))
+            },
+            (MessageId::GpsPos, Value::Floats(pos)) if !self.fused && pos.values.len() >= 2 => {
+                single("navigation.position", json!({ "latitude": pos.values[0], "longitude": pos.values[1] }))
+            },
+            (MessageId::FusedVelocity, Value::Floats(velocity)) if self.fused && velocity.values.len() >= 2 => {
+                // East and north in m/s
+                let (east, north) = (velocity.values[0] as f64, velocity.values[1] as f64);
+                vec![
+                    ("navigation.speedOverGround".to_string(), json!((east.powi(2) + north.powi(2)).sqrt())),
+                    ("navigation.courseOverGroundTrue".to_string(), json!(east.atan2(north).rem_euclid(std::f64::consts::TAU)))
+                ]
+            },
+            (MessageId::GpsSpeed, Value::Double(speed)) if !self.fused => single("navigation.speedOverGround", json!(speed * KNOTS_TO_MS)),
+            (MessageId::GpsCourse, Value::Double(course)) if !self.fused => single("navigation.courseOverGroundTrue", json!(course.to_radians())),
+            (MessageId::GpsMagneticVariation, Value::Double(variation)) => single("navigation.magneticVariation", json!(variation.to_radians())),
+            (MessageId::AttitudeHeadingTrue, Value::Float(heading)) => single("navigation.headingTrue", json!((*heading as f64).to_radians())),
+            (MessageId::AttitudeHeadingMagnetic, Value::Float(heading)) => single("navigation.headingMagnetic", json!((*heading as f64).to_radians())),
+            (MessageId::AttitudeRoll, Value::Float(roll)) => {
+                self.roll = Some((*roll as f64).to_radians());
+                Vec::new()
+            },
+            // Roll and pitch are sent one after the other
+            (MessageId::AttitudePitch, Value::Float(pitch)) => match self.roll {
+                Some(roll) => single("navigation.attitude", json!({ "roll": roll, "pitch": (*pitch as f64).to_radians(), "yaw": null })),
+                None => Vec::new()
+            },
+            (MessageId::WaterSpeed, Value::Float(speed)) => single("navigation.speedThroughWater", json!(*speed as f64 * KNOTS_TO_MS)),
+            (MessageId::EscInVoltage, Value::Float(voltage)) => single("electrical.batteries.main.voltage", json!(voltage)),
+            (MessageId::GlobalBatCurrent, Value::Float(current)) => single("electrical.batteries.main.current", json!(current)),
+            (MessageId::GlobalSoc, Value::Uint32(soc)) => single("electrical.batteries.main.capacity.stateOfCharge", json!(*soc as f64 / 100.0)),
+            (MessageId::BatTmax, Value::Uint32(temp)) => single("electrical.batteries.main.temperature", json!(*temp as f64 + CELSIUS_TO_KELVIN)),
+            // ERPM to revolutions per second
+            (MessageId::VescRpm, Value::StringFloatMap(map)) => {
+                let pole_pairs = self.pole_pairs;
+                self.propulsion(&map.items, "revolutions", |erpm| erpm / pole_pairs / 60.0)
+            },
+            (MessageId::VescMotorTemp, Value::StringFloatMap(map)) => self.propulsion(&map.items, "temperature", |temp| temp + CELSIUS_TO_KELVIN),
+            (_, value) => {
+                let name = id.as_str_name();
+                match (name.strip_prefix("BAT").and_then(|rest| rest.split_once('_')), number(value)) {
+                    (Some((pack, rest)), Some(value)) if !pack.is_empty() && pack.chars().all(|c| c.is_ascii_digit()) => self.pack(pack, rest, value),
+                    _ => Vec::new()
+                }
+            }
+        }
+    }
+}
+
+fn timestamp(msg: &BoatCoreMessage) -> String {
+    msg.timestamp.as_ref()
+        .and_then(|ts| DateTime::from_timestamp(ts.seconds, ts.nanos as u32))
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+fn self_context() -> String {
+    format!("vessels.{}", SETTINGS.get::<String>("signalk.uuid").unwrap())
+}
+
+// Values waiting to be sent to one client, only the latest per path is kept
+#[derive(Default)]
+struct PendingValues {
+    order: Vec<String>,
+    values: HashMap<String, (JsonValue, String)>
+}
+
+impl PendingValues {
+    fn push(&mut self, path: String, value: JsonValue, timestamp: String) {
+        if self.values.insert(path.clone(), (value, timestamp)).is_none() {
+            self.order.push(path);
+        }
+    }
+
+    // Delta with one update per timestamp
+    fn take_delta(&mut self, context: &str) -> Option<String> {
+        let mut updates: Vec<(String, Vec<JsonValue>)> = Vec::new();
+        for path in self.order.drain(..) {
+            let Some((value, timestamp)) = self.values.remove(&path) else { continue };
+            let value = json!({ "path": path, "value": value });
+            match updates.iter_mut().find(|(ts, _values)| *ts == timestamp) {
+                Some((_ts, values)) => values.push(value),
+                None => updates.push((timestamp, vec![value]))
+            }
+        }
+        if updates.is_empty() {
+            return None;
+        }
+        let updates: Vec<JsonValue> = updates.into_iter()
+            .map(|(timestamp, values)| json!({ "$source": "boat-core", "timestamp": timestamp, "values": values }))
+            .collect();
+        Some(json!({ "context": context, "updates": updates }).to_string())
+    }
+}
+
+// {"context": "vessels.self", "subscribe": [{"path": "navigation.*"}]} or {"context": "*", "unsubscribe": [{"path": "*"}]}
+#[derive(Deserialize)]
+struct SubscriptionPath {
+    path: String
+}
+
+#[derive(Deserialize)]
+struct SubscribeMessage {
+    subscribe: Option<Vec<SubscriptionPath>>,
+    unsubscribe: Option<Vec<SubscriptionPath>>
+}
+
+// SignalK discovery document served on GET /signalk
+pub fn discovery(host: &str) -> JsonValue {
+    json!({
+        "endpoints": {
+            "v1": {
+                "version": "1.7.0",
+                "signalk-ws": format!("ws://{}{}", host, STREAM_PATH)
+            }
+        },
+        "server": {
+            "id": "boat-core-v2",
+            "version": env!("CARGO_PKG_VERSION")
+        }
+    })
+}
+
+fn push_snapshot(pending: &Mutex<PendingValues>, metric_cache: &MetricCache, subscription: &Subscription) {
+    let mut mapper = SignalKMapper::new();
+    let mut snapshot = metric_cache.snapshot();
+    snapshot.sort_by_key(|msg| msg.id);
+    let mut pending = pending.lock().unwrap();
+    // Pending values may belong to the old subscription and are older than the snapshot
+    *pending = PendingValues::default();
+    for msg in snapshot {
+        let timestamp = timestamp(&msg);
+        for (path, value) in mapper.map(&msg) {
+            if subscription.matches_name(&path) {
+                pending.push(path, value, timestamp.clone());
+            }
+        }
+    }
+}
+
+// ?subscribe=none starts without subscription, self and all (the default) subscribe to every path
+pub async fn handle_client(query: Option<String>, stream: WebSocketStream<TcpStream>, metric_bus: MetricSender, metric_cache: MetricCache) {
+    info!("SignalK client connected");
+    let context = self_context();
+    let (mut out, mut inc) = stream.split();
+
+    let subscribe_none = query.is_some_and(|query| query.split('&').any(|param| param == "subscribe=none"));
+    let initial = if subscribe_none { Subscription::default() } else { Subscription::new(vec!["*".to_string()]) };
+    let (subscription_sender, mut subscription) = watch::channel(initial);
+    subscription.mark_changed();
+
+    let hello = json!({
+        "name": "boat-core-v2",
+        "version": "1.7.0",
+        "self": context,
+        "roles": ["master", "main"],
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    });
+    if out.send(Message::Text(hello.to_string())).await.is_err() {
+        return;
+    }
+
+    // Message bus to the client's pending values
+    let pending = Arc::new(Mutex::new(PendingValues::default()));
+    let notify = Arc::new(Notify::new());
+    let mut receiver = metric_bus.subscribe_as("signalk");
+    let forwarder = {
+        let pending = pending.clone();
+        let notify = notify.clone();
+        let subscription = subscription.clone();
+        tokio::spawn(async move {
+            let mut mapper = SignalKMapper::new();
+            while let Some(msg) = receiver.recv().await {
+                let values = mapper.map(&msg);
+                if values.is_empty() {
+                    continue;
+                }
+                let timestamp = timestamp(&msg);
+                let subscription = subscription.borrow().clone();
+                let mut pending = pending.lock().unwrap();
+                for (path, value) in values.into_iter().filter(|(path, _value)| subscription.matches_name(path)) {
+                    pending.push(path, value, timestamp.clone());
+                }
+                notify.notify_one();
+            }
+        })
+    };
+
+    // Pending values to ws as deltas
+    tokio::spawn(async move {
+        // Err once the reader task ended, the client is gone
+        while let Ok(changed) = subscription.has_changed() {
+            if changed {
+                let current = subscription.borrow_and_update().clone();
+                push_snapshot(&pending, &metric_cache, &current);
+            } else {
+                tokio::select! {
+                    _ = notify.notified() => {},
+                    changed = subscription.changed() => match changed {
+                        Ok(_) => continue,
+                        Err(_closed) => break
+                    }
+                }
+            }
+
+            let delta = pending.lock().unwrap().take_delta(&context);
+            if let Some(delta) = delta {
+                if let Err(err) = out.send(Message::Text(delta)).await {
+                    error!("Error when sending {}", err);
+                    break;
+                }
+            }
+        }
+        forwarder.abort();
+    });
+
+    // Subscription changes from the client
+    tokio::spawn(async move {
+        while let Some(Ok(msg)) = inc.next().await {
+            let Message::Text(text) = msg else { continue };
+            match serde_json::from_str::<SubscribeMessage>(&text) {
+                Ok(request) => subscription_sender.send_modify(|subscription| {
+                    if let Some(paths) = request.unsubscribe {
+                        subscription.remove(paths.into_iter().map(|path| path.path).collect());
+                    }
+                    if let Some(paths) = request.subscribe {
+                        subscription.add(paths.into_iter().map(|path| path.path).collect());
+                    }
+                }),
+                Err(err) => warn!("Invalid message from SignalK client: {}", err)
+            }
+        }
+    });
+}
@@ -0,0 +1,112 @@
+use serde::Deserialize;
+use wannsea_types::MessageId;
+
+// Set of MessageId name patterns a client wants to receive, e.g. "BAT*" or "ESC_RPM".
+// Patterns support * (any characters) and ? (one character) and ignore case, so "Esc*" works as well.
+#[derive(Clone, Debug, Default)]
+pub struct Subscription {
+    patterns: Vec<String>
+}
+
+// Control message of a client replacing its subscription, e.g. {"subscribe": ["BAT*", "ESC*"]}
+#[derive(Deserialize)]
+pub struct SubscribeRequest {
+    pub subscribe: Vec<String>
+}
+
+// Iterative with a single backtrack point (the last *), so patterns like "*a*a*a*b" stay O(pattern * name)
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position after the last * and the name position it currently covers up to
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            },
+            Some(c) if *c == b'?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            },
+            _ => match star {
+                // Let the last * cover one more character
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                },
+                None => return false
+            }
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+impl Subscription {
+    pub fn new(patterns: Vec<String>) -> Self {
+        Subscription { patterns }
+    }
+
+    // "/" subscribes to everything, otherwise the path is a comma separated list of patterns
+    pub fn from_path(path: &str) -> Self {
+        let path = path.trim_start_matches('/');
+        if path.is_empty() {
+            return Subscription::new(vec!["*".to_string()]);
+        }
+        Subscription::new(path.split(',').map(String::from).collect())
+    }
+
+    pub fn matches(&self, id: MessageId) -> bool {
+        self.matches_name(id.as_str_name())
+    }
+
+    // Any name, e.g. a SignalK path like "navigation.position"
+    pub fn matches_name(&self, name: &str) -> bool {
+        self.patterns.iter().any(|pattern| glob_match(pattern.as_bytes(), name.as_bytes()))
+    }
+
+    pub fn add(&mut self, patterns: Vec<String>) {
+        for pattern in patterns {
+            if !self.patterns.contains(&pattern) {
+                self.patterns.push(pattern);
+            }
+        }
+    }
+
+    // "*" removes every pattern, others only remove the exact same pattern
+    pub fn remove(&mut self, patterns: Vec<String>) {
+        if patterns.iter().any(|pattern| pattern == "*") {
+            self.patterns.clear();
+        } else {
+            self.patterns.retain(|pattern| !patterns.contains(pattern));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        glob_match(pattern.as_bytes(), name.as_bytes())
+    }
+
+    #[test]
+    fn matches_wildcards_ignoring_case() {
+        assert!(matches("*", "BAT1_U1"));
+        assert!(matches("bat*", "BAT1_U1"));
+        assert!(matches("BAT?_U1", "BAT1_U1"));
+        assert!(matches("*_U1*", "BAT1_U14"));
+        assert!(matches("navigation.*", "navigation.position"));
+        assert!(!matches("BAT?_U1", "BAT10_U1"));
+        assert!(!matches("ESC*", "BAT1_U1"));
+        assert!(!matches("", "BAT1_U1"));
+    }
+
+    #[test]
+    fn does_not_backtrack_exponentially() {
+        let name = "a".repeat(10_000);
+        assert!(!matches("*a*a*a*a*a*a*a*a*b", &name));
+    }
+}
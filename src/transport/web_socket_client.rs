@@ -1,11 +1,11 @@
 
 
 use futures::{StreamExt, SinkExt};
-use log::{debug, info, warn};
+use log::{debug, info};
 use tokio_tungstenite::connect_async;
 use wannsea_types::BoatCoreMessage;
 use prost::Message;
-use crate::{helper::MetricSender, SETTINGS};
+use crate::{helper::{bus::BusExt, MetricSender}, SETTINGS};
 
 use super::metric_queue::MetricQueue;
 
@@ -45,7 +45,7 @@ impl WebSocketClient {
             let (mut write, _read) = websocket_res.unwrap().0.split();
             
             loop {
-                let msg: BoatCoreMessage = metric_queue.pop().await;
+                let Some(msg) = metric_queue.pop().await else { return };
                 let mut buf = Vec::new();
                 buf.reserve(msg.encoded_len());
                 msg.encode(&mut buf).unwrap();
@@ -70,14 +70,9 @@ impl WebSocketClient {
             let metric_sender = self.metric_sender.clone();
             let metric_queue = self.cached_messages.clone();
             tokio::spawn(async move {
-                let mut receiver = metric_sender.subscribe();
-                loop {
-                    match receiver.recv().await {
-                        Ok(msg) => {
-                            metric_queue.push(msg).await;
-                        },
-                        Err(err) => warn!("Error while receiving from Metric Bus: {:?}", err),
-                    }
+                let mut receiver = metric_sender.subscribe_as("ws_client");
+                while let Some(msg) = receiver.recv().await {
+                    metric_queue.push(msg).await;
                 }
             });
         }
@@ -1,69 +1,176 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
 use futures::{StreamExt, SinkExt};
 use log::{error, info, warn};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncRead, AsyncWrite}};
-use tokio_tungstenite::{tungstenite::{Message, self, handshake::server::{Request, Response, ErrorResponse}}, WebSocketStream};
-use wannsea_types::BoatCoreMessage;
-use crate::{SETTINGS, helper::MetricSender};
+use tokio::{net::{TcpListener, TcpStream}, io::{AsyncRead, AsyncWrite}, sync::{watch, Notify}};
+use tokio_tungstenite::{tungstenite::{Message, self, handshake::server::{Request, Response, ErrorResponse}, http::HeaderValue}, WebSocketStream};
+use wannsea_types::{BoatCoreMessage, MessageId};
+use crate::{SETTINGS, helper::{bus::BusExt, metric_cache::{merge_latest, MetricCache}, MetricSender}};
+
+use super::{command, encoding::Encoding, http_api, signalk, subscription::{SubscribeRequest, Subscription}};
 
 pub struct WebSocketServer {
-    message_bus: MetricSender
+    message_bus: MetricSender,
+    metric_cache: MetricCache
 } 
+// Encoding from the ?encoding= query parameter, otherwise the first supported subprotocol, otherwise JSON
+fn negotiate_encoding(req: &Request) -> (Encoding, bool) {
+    let query_encoding = req.uri().query().and_then(|query| {
+        query.split('&').find_map(|param| param.strip_prefix("encoding=")).and_then(Encoding::from_name)
+    });
+    if let Some(encoding) = query_encoding {
+        return (encoding, false);
+    }
+    let protocol_encoding = req.headers()
+        .get("Sec-WebSocket-Protocol")
+        .and_then(|protocols| protocols.to_str().ok())
+        .and_then(|protocols| protocols.split(',').find_map(Encoding::from_name));
+    match protocol_encoding {
+        Some(encoding) => (encoding, true),
+        None => (Encoding::Json, false)
+    }
+}
+
 async fn handle_raw_socket<T: AsyncRead + AsyncWrite + Unpin>(
     socket: T
-) -> (Result<WebSocketStream<T>, tungstenite::Error>, Option<String>) {
-    let mut path = None;
-    let callback = |req: &Request, res: Response| -> Result<Response, ErrorResponse> {
-        path = Some(req.uri().path().to_string());
+) -> (Result<WebSocketStream<T>, tungstenite::Error>, Option<(String, Option<String>, Encoding)>) {
+    let mut client = None;
+    let callback = |req: &Request, mut res: Response| -> Result<Response, ErrorResponse> {
+        let (encoding, subprotocol) = negotiate_encoding(req);
+        if subprotocol {
+            // Browsers close the connection if the requested subprotocol is not confirmed
+            res.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(encoding.name()));
+        }
+        client = Some((req.uri().path().to_string(), req.uri().query().map(String::from), encoding));
         Ok(res)
     };
-    (tokio_tungstenite::accept_hdr_async(socket, callback).await, path)
+    (tokio_tungstenite::accept_hdr_async(socket, callback).await, client)
 }
 
 
-async fn handle_client(path: String, stream: WebSocketStream<TcpStream>, metric_bus: MetricSender) {   
-    info!("WebSocket connection established: {}", path);
+// Messages waiting to be sent to one client. Only the latest message per id is kept, so a slow client
+// gets fewer updates instead of lagging behind or holding up the metric bus.
+#[derive(Default)]
+struct Conflated {
+    order: Vec<MessageId>,
+    latest: HashMap<MessageId, BoatCoreMessage>
+}
+
+impl Conflated {
+    fn push(&mut self, msg: BoatCoreMessage) {
+        let id = msg.id();
+        if merge_latest(&mut self.latest, msg) {
+            self.order.push(id);
+        }
+    }
+
+    fn take(&mut self) -> Vec<BoatCoreMessage> {
+        let mut latest = std::mem::take(&mut self.latest);
+        self.order.drain(..).filter_map(|id| latest.remove(&id)).collect()
+    }
+}
+
+async fn send_all<S: SinkExt<Message> + Unpin>(out: &mut S, encoding: Encoding, messages: Vec<BoatCoreMessage>) -> Result<(), S::Error> {
+    for msg in messages {
+        out.send(encoding.encode(&msg)).await?;
+    }
+    Ok(())
+}
+
+// The path sets the initial subscription, clients change it at runtime by sending {"subscribe": [...]}.
+// After connecting and after every subscription change the client gets the latest value of each subscribed metric.
+async fn handle_client(path: String, authenticated: bool, encoding: Encoding, stream: WebSocketStream<TcpStream>, metric_bus: MetricSender, metric_cache: MetricCache) {   
+    info!("WebSocket connection established: {} ({})", path, encoding.name());
     let (mut out, mut inc) = stream.split();
+    let (subscription_sender, mut subscription) = watch::channel(Subscription::from_path(&path));
+    subscription.mark_changed();
 
-    // Message bus to ws
-    let mut receiver = metric_bus.subscribe();
+    // Message bus to the client's conflation buffer
+    let pending = Arc::new(Mutex::new(Conflated::default()));
+    let notify = Arc::new(Notify::new());
+    let mut receiver = metric_bus.subscribe_as("ws_server");
+    let forwarder = {
+        let pending = pending.clone();
+        let notify = notify.clone();
+        let subscription = subscription.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = receiver.recv().await {
+                if subscription.borrow().matches(msg.id()) {
+                    pending.lock().unwrap().push(msg);
+                    notify.notify_one();
+                }
+            }
+        })
+    };
+
+    // Conflation buffer to ws
     tokio::spawn(async move {
         loop {
+            // Err once the reader task ended, the client is gone
+            let Ok(changed) = subscription.has_changed() else { break };
+            if changed {
+                let current = subscription.borrow_and_update().clone();
+                let snapshot: Vec<BoatCoreMessage> = metric_cache.snapshot().into_iter().filter(|msg| current.matches(msg.id())).collect();
+                // Pending messages may belong to the old subscription and are older than the snapshot
+                pending.lock().unwrap().take();
+                if let Err(err) = send_all(&mut out, encoding, snapshot).await {
+                    error!("Error when sending {}", err);
+                    break;
+                }
+            }
 
-            match receiver.recv().await {
-                Ok(msg) => {
-                    if path.to_lowercase() == "/" || msg.id().as_str_name() == &path[1..] {
-                        let json = serde_json::to_string(&msg).unwrap();
-                        if let Err(err) = out.send(Message::Text(json)).await {
-                            error!("Error when sending {}", err);
-                            break;
-                        }
-                    }
-                },
-                Err(err) => warn!("Error while receiving from Metric Bus: {:?}", err),
+            tokio::select! {
+                _ = notify.notified() => {},
+                changed = subscription.changed() => match changed {
+                    Ok(_) => continue,
+                    Err(_closed) => break
+                }
             }
 
-          
+            let messages = pending.lock().unwrap().take();
+            if let Err(err) = send_all(&mut out, encoding, messages).await {
+                error!("Error when sending {}", err);
+                break;
+            }
         }
+        forwarder.abort();
     });
 
-    // Ws to message bus
-//  let metric_sender = metric_bus.clone();
-//  tokio::spawn(async move {
-//      loop {
-//          if let Some(Ok(msg)) = inc.next().await {
-//              if let Ok(text) = msg.to_text() {
-//                 if let Ok(bcm) = serde_json::from_str::<BoatCoreMessage>(text) {
-//                      metric_sender.send(bcm).unwrap();
-//                 }
-//              }
-//          }
-//      }
-//  });
+    // Ws to message bus, used by clients to send commands (e.g. AnchorWatchCommand, MobCommand) if ws-server.commands is set
+    // Text frames are always JSON, binary frames use the encoding of the connection
+    let accept_commands = SETTINGS.get::<bool>("ws-server.commands").unwrap();
+    tokio::spawn(async move {
+        while let Some(Ok(msg)) = inc.next().await {
+            let command = match msg {
+                Message::Text(text) => {
+                    if let Ok(request) = serde_json::from_str::<SubscribeRequest>(&text) {
+                        info!("WebSocket client {} subscribed to {:?}", path, request.subscribe);
+                        let _ = subscription_sender.send(Subscription::new(request.subscribe));
+                        continue;
+                    }
+                    serde_json::from_str::<BoatCoreMessage>(&text).map_err(|err| err.to_string())
+                },
+                Message::Binary(data) => encoding.decode(&data),
+                _ => continue
+            };
+            if !accept_commands {
+                warn!("Ignoring command from WebSocket client {}, ws-server.commands is disabled", path);
+                continue;
+            }
+            match command.and_then(|bcm| command::check(&bcm, authenticated).map(|_| bcm)) {
+                Ok(bcm) => {
+                    let _ = metric_bus.send(bcm);
+                },
+                Err(err) => warn!("Invalid message from WebSocket client: {}", err)
+            }
+        }
+    });
 }
 
 impl WebSocketServer {
-    pub fn new(message_bus: MetricSender) -> Self {
-        WebSocketServer { message_bus }
+    pub fn new(message_bus: MetricSender, metric_cache: MetricCache) -> Self {
+        WebSocketServer { message_bus, metric_cache }
     }
 
     pub fn start(&self) {
@@ -71,6 +178,7 @@ impl WebSocketServer {
             info!("WebSocket Server enabled!");
 
             let message_bus = self.message_bus.clone();
+            let metric_cache = self.metric_cache.clone();
             tokio::spawn(async move {
                 let addr = SETTINGS.get::<String>("ws-server.address").unwrap();
                 let try_socket = TcpListener::bind(&addr).await;
@@ -79,14 +187,35 @@ impl WebSocketServer {
     
                 // Let's spawn the handling of each connection in a separate task.
                 while let Ok((stream, _addr)) = listener.accept().await {       
-                    let ws = handle_raw_socket(stream).await;
-                    match ws {
-                        (Ok(ws), Some(path)) =>  {
-                            println!("WS CONNECT {}", path);
-                            handle_client(path, ws, message_bus.clone()).await;
-                        },
-                        _ => error!("Ws Connect error")
-                    }
+                    let message_bus = message_bus.clone();
+                    let metric_cache = metric_cache.clone();
+                    tokio::spawn(async move {
+                        // Requests without a WebSocket upgrade go to the HTTP API
+                        match http_api::peek_request(&stream).await {
+                            Some((request, false)) => {
+                                http_api::handle_request(stream, request, message_bus, metric_cache).await;
+                                return;
+                            },
+                            Some((_request, true)) => {},
+                            None => {
+                                error!("Invalid request on WebSocket server");
+                                return;
+                            }
+                        }
+                        let ws = handle_raw_socket(stream).await;
+                        match ws {
+                            (Ok(ws), Some((path, query, _encoding))) if path == signalk::STREAM_PATH && SETTINGS.get::<bool>("signalk.enabled").unwrap() => {
+                                signalk::handle_client(query, ws, message_bus, metric_cache).await;
+                            },
+                            (Ok(ws), Some((path, query, encoding))) =>  {
+                                println!("WS CONNECT {}", path);
+                                // Motor commands need ?token=<throttle.remote_token>
+                                let token = query.as_deref().and_then(|query| query.split('&').find_map(|param| param.strip_prefix("token=")));
+                                handle_client(path, command::is_authenticated(token), encoding, ws, message_bus, metric_cache).await;
+                            },
+                            _ => error!("Ws Connect error")
+                        }
+                    });
                 }
             });
         }